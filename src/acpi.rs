@@ -0,0 +1,119 @@
+use crate::memory;
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+/// Header shared by every ACPI system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the following fields are only valid for revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Returns the physical address of the table with the given signature (for
+/// example `b"HPET"` or `b"APIC"`).
+///
+/// Returns `None` if the physical memory has not been mapped yet through
+/// `memory::init`, if no RSDP could be found, or if no valid table with that
+/// signature is listed in the RSDT/XSDT.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+
+    let root_header = unsafe { read_header(root)? };
+    let header_size = mem::size_of::<SdtHeader>();
+    let entries = (root_header.length as usize).saturating_sub(header_size) / entry_size;
+    let entries_start = memory::phys_to_virt(root + header_size as u64)?;
+
+    for i in 0..entries {
+        let entry_ptr = entries_start + (i * entry_size) as u64;
+        let table = unsafe {
+            if entry_size == 8 {
+                core::ptr::read_unaligned(entry_ptr.as_ptr::<u64>())
+            } else {
+                u64::from(core::ptr::read_unaligned(entry_ptr.as_ptr::<u32>()))
+            }
+        };
+        let table = PhysAddr::new(table);
+        if let Some(header) = unsafe { read_header(table) } {
+            if &header.signature == signature {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Reads and validates the header of the system description table at the
+/// given physical address.
+///
+/// This function is unsafe because the caller must guarantee that `addr`
+/// points to a system description table.
+pub unsafe fn read_header(addr: PhysAddr) -> Option<SdtHeader> {
+    let virt = memory::phys_to_virt(addr)?;
+    let header = core::ptr::read_unaligned(virt.as_ptr::<SdtHeader>());
+    let length = header.length as usize;
+    if length < mem::size_of::<SdtHeader>() {
+        return None;
+    }
+    if checksum(slice::from_raw_parts(virt.as_ptr::<u8>(), length)) != 0 {
+        return None;
+    }
+    Some(header)
+}
+
+/// Searches the first KiB of the EBDA and the BIOS ROM area for the RSDP.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment = unsafe {
+        let ptr = memory::phys_to_virt(PhysAddr::new(0x40e))?.as_ptr::<u16>();
+        core::ptr::read_unaligned(ptr)
+    };
+    let ebda_start = u64::from(ebda_segment) << 4;
+
+    let areas = [(ebda_start, ebda_start + 1024), (0xe_0000, 0x10_0000)];
+    for &(start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+        for addr in (start..end).step_by(16) {
+            let virt = memory::phys_to_virt(PhysAddr::new(addr))?;
+            let bytes = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), RSDP_V1_LENGTH) };
+            if &bytes[..8] != RSDP_SIGNATURE || checksum(bytes) != 0 {
+                continue;
+            }
+            return Some(unsafe { core::ptr::read_unaligned(virt.as_ptr::<Rsdp>()) });
+        }
+    }
+    None
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
 
 use x86_64::VirtAddr;
 use x86_64::structures::paging::{
@@ -7,6 +8,8 @@ use x86_64::structures::paging::{
     PageTableFlags, Size4KiB,
 };
 
+use crate::sync::IrqSafeMutex;
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;  // 100 KiB
 pub struct Dummy;
@@ -21,6 +24,19 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+unsafe impl GlobalAlloc for IrqSafeMutex<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_first_fit(layout)
+            .ok()
+            .map_or(null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
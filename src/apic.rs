@@ -0,0 +1,142 @@
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const REG_ID: usize = 0x020;
+const REG_EOI: usize = 0x0b0;
+const REG_SPURIOUS: usize = 0x0f0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_PERFORMANCE: usize = 0x340;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Vector raised by the local APIC for spurious interrupts. It must not be
+/// acknowledged with an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Virtual address of the local APIC registers, or 0 while it is disabled.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the local APIC of the current CPU.
+///
+/// The APIC registers are accessed through the physical memory mapping, so
+/// this must be called after `memory::init`. Returns `false` if the CPU has
+/// no local APIC.
+pub fn init() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let has_apic = unsafe { __cpuid(1) }.edx & (1 << 9) != 0;
+    if !has_apic {
+        return false;
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    let phys = PhysAddr::new(base & APIC_BASE_ADDRESS_MASK);
+    let virt = match memory::phys_to_virt(phys) {
+        Some(virt) => virt,
+        None => return false,
+    };
+
+    unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+    LAPIC_BASE.store(virt.as_u64(), Ordering::Release);
+
+    unsafe {
+        let spurious = read(REG_SPURIOUS);
+        write(REG_SPURIOUS, spurious | SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+    true
+}
+
+/// Returns `true` once the local APIC has been enabled by `init`.
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Acquire) != 0
+}
+
+/// Returns the local APIC ID of the current CPU.
+pub fn id() -> u32 {
+    if is_enabled() {
+        unsafe { read(REG_ID) >> 24 }
+    } else {
+        0
+    }
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    if is_enabled() {
+        unsafe { write(REG_EOI, 0) };
+    }
+}
+
+/// Delivers performance counter overflows as NMIs. The entry is masked by
+/// the CPU on every delivery, so this must be called again to re-arm it.
+pub fn route_performance_counter_to_nmi() {
+    if is_enabled() {
+        unsafe { write(REG_LVT_PERFORMANCE, LVT_DELIVERY_NMI) };
+    }
+}
+
+/// Sends an INIT IPI to the CPU with the given local APIC ID, which resets
+/// it into the wait-for-SIPI state.
+pub fn send_init(apic_id: u32) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI to the CPU with the given local APIC ID. The CPU
+/// starts executing in real mode at the beginning of the physical page with
+/// number `page`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_command(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// Sends an interrupt on the given IDT vector to the CPU with the given
+/// local APIC ID.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send_command(apic_id, ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// Writes `command` to the interrupt command register and waits until the
+/// IPI was sent.
+fn send_command(apic_id: u32, command: u32) {
+    if !is_enabled() {
+        return;
+    }
+    // the destination must not change between the two writes
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+/// Reads a local APIC register.
+///
+/// This function is unsafe because the local APIC must have been enabled
+/// and `register` must be a valid register offset.
+pub unsafe fn read(register: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::read_volatile((base + register) as *const u32)
+}
+
+/// Writes a local APIC register.
+///
+/// This function is unsafe because the local APIC must have been enabled
+/// and `register` must be a valid register offset.
+pub unsafe fn write(register: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::write_volatile((base + register) as *mut u32, value);
+}
@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Maximum number of bottom halves that can be registered.
+pub const MAX_BOTTOM_HALVES: usize = 32;
+
+/// Number of times `run_pending` re-checks for newly raised bottom halves
+/// before leaving them to the next call.
+const MAX_ROUNDS: usize = 8;
+
+static HANDLERS: RwLock<[Option<fn()>; MAX_BOTTOM_HALVES]> =
+    RwLock::new([None; MAX_BOTTOM_HALVES]);
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// Work deferred from an interrupt handler. Raising it is lock-free and may
+/// be done from any context; the handler later runs with interrupts enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BottomHalf(usize);
+
+impl BottomHalf {
+    /// Marks the bottom half as pending. It runs once on the next call to
+    /// `run_pending`, however often it was raised before.
+    pub fn raise(self) {
+        PENDING.fetch_or(1 << self.0, Ordering::Release);
+    }
+}
+
+/// Registers `handler` as a new bottom half. Returns `None` if all
+/// `MAX_BOTTOM_HALVES` slots are in use.
+pub fn register(handler: fn()) -> Option<BottomHalf> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let index = handlers.iter().position(Option::is_none)?;
+        handlers[index] = Some(handler);
+        Some(BottomHalf(index))
+    })
+}
+
+/// Returns `true` if any bottom half was raised and has not run yet.
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+}
+
+/// Runs the pending bottom halves with interrupts enabled.
+///
+/// Called from the idle loop, never from interrupt context.
+pub fn run_pending() {
+    for _ in 0..MAX_ROUNDS {
+        let pending = PENDING.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            return;
+        }
+
+        let handlers = *HANDLERS.read();
+        for (index, handler) in handlers.iter().enumerate() {
+            if pending & (1 << index) == 0 {
+                continue;
+            }
+            if let Some(handler) = handler {
+                handler();
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_raise_runs_once() {
+    use core::sync::atomic::AtomicUsize;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_run() {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    serial_print!("test_raise_runs_once... ");
+    let bottom_half = register(count_run).expect("no free bottom half");
+    bottom_half.raise();
+    bottom_half.raise();
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,255 @@
+// Bounded channels whose senders neither block nor allocate, so that
+// interrupt handlers can hand data to threads and tasks. The buffer is
+// allocated when the channel is created.
+//
+// A channel is closed once its receiver is dropped or closed, or once all
+// its senders are dropped. Values still queued can be received after the
+// senders are gone; sending to a closed channel fails.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use self::ring::Ring;
+use crate::sync::WaitQueue;
+
+pub mod mpsc;
+mod ring;
+pub mod spsc;
+
+/// Returned by `send` if the value could not be queued, together with the
+/// value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The channel is full. The value is counted as dropped.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Full(value) | SendError::Closed(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// The channel is empty and no more values can arrive.
+    Closed,
+}
+
+/// Returned by `recv` once the channel is empty and closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+/// The state shared by the senders and the receiver of a channel.
+struct Shared<T> {
+    ring: Ring<T>,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    /// The task waiting in `Receiver::poll_recv`.
+    waker: AtomicWaker,
+    /// The thread waiting in `Receiver::recv`.
+    waiters: WaitQueue,
+}
+
+// The ring is only pushed to and popped from as its kind allows, which the
+// sender types and `&mut Receiver` ensure.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn new(ring: Ring<T>) -> Arc<Shared<T>> {
+        Arc::new(Shared {
+            ring,
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            waker: AtomicWaker::new(),
+            waiters: WaitQueue::new(),
+        })
+    }
+
+    /// This function is unsafe because an SPSC channel must only be sent to
+    /// by one sender at a time.
+    unsafe fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(SendError::Closed(value));
+        }
+        match self.ring.push(value) {
+            Ok(()) => {
+                self.waker.wake();
+                self.waiters.notify_one();
+                Ok(())
+            }
+            Err(value) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Err(SendError::Full(value))
+            }
+        }
+    }
+
+    /// This function is unsafe because it must only be called by the
+    /// receiver.
+    unsafe fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.ring.pop() {
+            return Ok(value);
+        }
+        if !self.closed.load(Ordering::Acquire) {
+            return Err(TryRecvError::Empty);
+        }
+        // a value may have been queued just before the channel was closed
+        self.ring.pop().ok_or(TryRecvError::Closed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.waker.wake();
+        self.waiters.notify_all();
+    }
+
+    fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn remove_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.close();
+        }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        while let Some(value) = unsafe { self.ring.pop() } {
+            drop(value);
+        }
+    }
+}
+
+/// The receiving half of a channel. Values can be received by blocking
+/// the running thread, by awaiting `recv_async` or as a `Stream`.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Removes the oldest value without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        unsafe { self.shared.try_recv() }
+    }
+
+    /// Removes the oldest value, parking the running thread until one
+    /// arrives. Fails once the channel is empty and closed.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        let mut received = Err(RecvError);
+        // the queue is locked while checking, so a value sent after the
+        // check notifies this thread
+        shared
+            .waiters
+            .wait_until(|| match unsafe { shared.try_recv() } {
+                Ok(value) => {
+                    received = Ok(value);
+                    true
+                }
+                Err(TryRecvError::Closed) => true,
+                Err(TryRecvError::Empty) => false,
+            });
+        received
+    }
+
+    /// Returns a future that resolves to the oldest value, or to an error
+    /// once the channel is empty and closed.
+    pub fn recv_async(&mut self) -> Recv<T> {
+        Recv { receiver: self }
+    }
+
+    /// Polls for the oldest value, registering the task to be woken if
+    /// there is none yet.
+    pub fn poll_recv(&mut self, context: &mut Context) -> Poll<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // register before checking again, so that a value sent in between
+        // still wakes the task
+        self.shared.waker.register(context.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.shared.waker.take();
+                Poll::Ready(Ok(value))
+            }
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Closes the channel, so that further sends fail. Values queued
+    /// before can still be received.
+    pub fn close(&mut self) {
+        self.shared.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of queued values.
+    pub fn len(&self) -> usize {
+        self.shared.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of values that can be queued at once. It is the
+    /// requested capacity rounded up to a power of two.
+    pub fn capacity(&self) -> usize {
+        self.shared.ring.capacity()
+    }
+
+    /// Returns the number of values dropped because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(context).map(Result::ok)
+    }
+}
+
+/// The future returned by `Receiver::recv_async`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T, RecvError>> {
+        self.get_mut().receiver.poll_recv(context)
+    }
+}
@@ -0,0 +1,53 @@
+use super::ring::{MpscRing, Ring};
+use super::{Receiver, SendError, Shared};
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+
+/// Creates a channel with any number of senders and a single receiver,
+/// which holds at least `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new(Ring::Mpsc(MpscRing::new(capacity)));
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+/// The sending half of an MPSC channel. It can be cloned, and can be used
+/// from several threads and interrupt handlers at the same time.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues a value without blocking. Fails if the channel is full or
+    /// closed.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        // the ring takes any number of producers
+        unsafe { self.shared.send(value) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of values dropped because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.add_sender();
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.remove_sender();
+    }
+}
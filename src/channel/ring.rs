@@ -0,0 +1,195 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The buffer of a channel. Both kinds have a power of two capacity and
+/// never allocate after they were created.
+pub(super) enum Ring<T> {
+    Spsc(SpscRing<T>),
+    Mpsc(MpscRing<T>),
+}
+
+impl<T> Ring<T> {
+    /// Appends a value, or hands it back if the ring is full.
+    ///
+    /// This function is unsafe because an SPSC ring must only be pushed to
+    /// by one producer at a time.
+    pub(super) unsafe fn push(&self, value: T) -> Result<(), T> {
+        match self {
+            Ring::Spsc(ring) => ring.push(value),
+            Ring::Mpsc(ring) => ring.push(value),
+        }
+    }
+
+    /// Removes the oldest value.
+    ///
+    /// This function is unsafe because rings must only be popped from by
+    /// one consumer at a time.
+    pub(super) unsafe fn pop(&self) -> Option<T> {
+        match self {
+            Ring::Spsc(ring) => ring.pop(),
+            Ring::Mpsc(ring) => ring.pop(),
+        }
+    }
+
+    pub(super) fn capacity(&self) -> usize {
+        match self {
+            Ring::Spsc(ring) => ring.slots.len(),
+            Ring::Mpsc(ring) => ring.slots.len(),
+        }
+    }
+
+    /// Returns the number of queued values. It may be outdated as soon as it
+    /// is returned.
+    pub(super) fn len(&self) -> usize {
+        let (pushed, popped) = match self {
+            Ring::Spsc(ring) => (&ring.head, &ring.tail),
+            Ring::Mpsc(ring) => (&ring.enqueue, &ring.dequeue),
+        };
+        let popped = popped.load(Ordering::Acquire);
+        pushed
+            .load(Ordering::Acquire)
+            .wrapping_sub(popped)
+            .min(self.capacity())
+    }
+}
+
+/// A ring with a single producer and a single consumer. The producer only
+/// writes the slot at `head` and the consumer only reads the slot at `tail`.
+pub(super) struct SpscRing<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Total number of values pushed, only written by the producer.
+    head: AtomicUsize,
+    /// Total number of values popped, only written by the consumer.
+    tail: AtomicUsize,
+}
+
+impl<T> SpscRing<T> {
+    pub(super) fn new(capacity: usize) -> SpscRing<T> {
+        let slots: Vec<_> = (0..round_capacity(capacity))
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect();
+        SpscRing {
+            slots: slots.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    unsafe fn push(&self, value: T) -> Result<(), T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == self.slots.len() {
+            return Err(value);
+        }
+        (*self.slot(head)).as_mut_ptr().write(value);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    unsafe fn pop(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let value = (*self.slot(tail)).as_ptr().read();
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        self.slots[position & (self.slots.len() - 1)].get()
+    }
+}
+
+/// A slot of an `MpscRing`. Its sequence number tells which lap of the
+/// ring it is in, and whether it holds a value for that lap.
+struct Sequenced<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A ring with any number of producers and a single consumer, after Dmitry
+/// Vyukov's bounded queue.
+///
+/// A producer claims a slot by advancing `enqueue` and publishes the value
+/// by advancing the slot's sequence number. Producers never wait for each
+/// other, so it can be pushed to from interrupt handlers, even if they
+/// interrupted a push.
+pub(super) struct MpscRing<T> {
+    slots: Box<[Sequenced<T>]>,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+}
+
+impl<T> MpscRing<T> {
+    pub(super) fn new(capacity: usize) -> MpscRing<T> {
+        let slots: Vec<_> = (0..round_capacity(capacity))
+            .map(|position| Sequenced {
+                sequence: AtomicUsize::new(position),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        MpscRing {
+            slots: slots.into_boxed_slice(),
+            enqueue: AtomicUsize::new(0),
+            dequeue: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lap = sequence.wrapping_sub(position) as isize;
+            if lap == 0 {
+                match self.enqueue.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.sequence
+                            .store(position.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lap < 0 {
+                // the slot still holds the value of the previous lap
+                return Err(value);
+            } else {
+                position = self.enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    unsafe fn pop(&self) -> Option<T> {
+        let position = self.dequeue.load(Ordering::Relaxed);
+        let slot = self.slot(position);
+        if slot.sequence.load(Ordering::Acquire) != position.wrapping_add(1) {
+            return None;
+        }
+        let value = (*slot.value.get()).as_ptr().read();
+        slot.sequence
+            .store(position.wrapping_add(self.slots.len()), Ordering::Release);
+        self.dequeue
+            .store(position.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    fn slot(&self, position: usize) -> &Sequenced<T> {
+        &self.slots[position & (self.slots.len() - 1)]
+    }
+}
+
+fn round_capacity(capacity: usize) -> usize {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    capacity.next_power_of_two()
+}
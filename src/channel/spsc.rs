@@ -0,0 +1,45 @@
+use super::ring::{Ring, SpscRing};
+use super::{Receiver, SendError, Shared};
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+
+/// Creates a channel with a single sender and a single receiver, which
+/// holds at least `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new(Ring::Spsc(SpscRing::new(capacity)));
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+/// The sending half of an SPSC channel. It cannot be cloned, and sending
+/// needs `&mut self`, so an interrupt handler sharing it with a thread must
+/// reach it through an `IrqSafeMutex`.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues a value without blocking. Fails if the channel is full or
+    /// closed.
+    pub fn send(&mut self, value: T) -> Result<(), SendError<T>> {
+        // `&mut self` makes this the only producer
+        unsafe { self.shared.send(value) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of values dropped because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.remove_sender();
+    }
+}
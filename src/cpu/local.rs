@@ -0,0 +1,86 @@
+use super::{id, MAX_CPUS};
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Declares a static with one value per CPU. Every value starts out as the
+/// given initializer, which must be usable in a `static`.
+///
+/// ```ignore
+/// cpu_local! {
+///     static TICKS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// TICKS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! cpu_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;) => {
+        // one initializer per CPU, see `cpu::MAX_CPUS`
+        $(#[$attr])*
+        $vis static $name: $crate::cpu::CpuLocal<$t> = $crate::cpu::CpuLocal::new([
+            $init, $init, $init, $init, $init, $init, $init, $init,
+        ]);
+    };
+}
+
+/// A value per CPU, declared with `cpu_local!`.
+pub struct CpuLocal<T> {
+    values: [T; MAX_CPUS],
+}
+
+// Each CPU only hands out its own value, unless `T` can be shared anyway.
+unsafe impl<T: Send> Sync for CpuLocal<T> {}
+
+impl<T> CpuLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> CpuLocal<T> {
+        CpuLocal { values }
+    }
+
+    /// Calls `f` with the value of the current CPU. Interrupts are disabled
+    /// meanwhile, so that neither an interrupt handler on this CPU nor a
+    /// thread switch can get in between.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&self.values[id()]))
+    }
+}
+
+impl<T: Sync> CpuLocal<T> {
+    /// Returns the value of the current CPU.
+    pub fn get(&self) -> &T {
+        &self.values[id()]
+    }
+
+    /// Returns the value of the given CPU.
+    pub fn get_for(&self, cpu: usize) -> &T {
+        &self.values[cpu]
+    }
+
+    /// Returns the values of all CPUs, indexed by CPU index.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.values.iter()
+    }
+}
+
+#[test_case]
+fn test_cpu_local() {
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    serial_print!("test_cpu_local... ");
+    cpu_local! {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    }
+    cpu_local! {
+        static FLAG: Cell<bool> = Cell::new(false);
+    }
+
+    COUNTER.get().fetch_add(2, Ordering::Relaxed);
+    assert_eq!(COUNTER.get_for(id()).load(Ordering::Relaxed), 2);
+    assert_eq!(COUNTER.iter().map(|c| c.load(Ordering::Relaxed)).sum::<usize>(), 2);
+
+    FLAG.with(|flag| flag.set(true));
+    assert!(FLAG.with(Cell::get));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,234 @@
+use alloc::boxed::Box;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::gdt::{self, Gdt};
+use crate::thread::stack::KernelStack;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+mod local;
+
+pub use self::local::CpuLocal;
+
+/// Maximum number of CPUs the kernel supports.
+pub const MAX_CPUS: usize = 8;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Offsets of the fields of the per-CPU block that are read through `%gs`
+/// directly.
+pub const SELF_OFFSET: usize = 0;
+pub const ID_OFFSET: usize = 8;
+pub const SCRATCH_OFFSET: usize = 16;
+pub const TSS_OFFSET: usize = 48;
+/// Offset of `privilege_stack_table[0]` of the TSS, the stack pointer loaded
+/// on interrupts from user mode.
+pub const RSP0_OFFSET: usize = TSS_OFFSET + 4;
+
+/// Number of 64 bit scratch slots in the per-CPU block.
+pub const SCRATCH_SLOTS: usize = 4;
+
+/// The data every CPU keeps for itself. The GS base of each CPU points to
+/// its block while it runs kernel code.
+///
+/// While user code runs, `IA32_KERNEL_GS_BASE` points to the block instead,
+/// and the interrupt entry code exchanges it with the GS base by `swapgs`
+/// before using `%gs`. The scratch slots can be used to save registers
+/// through `%gs` where no stack is available.
+// the fields before the TSS are only read through `%gs`
+#[allow(dead_code)]
+#[repr(C)]
+pub(crate) struct PerCpu {
+    /// Points to the block itself, so that its address can be read with a
+    /// single `mov %gs:0`.
+    self_ptr: *const PerCpu,
+    id: usize,
+    scratch: [u64; SCRATCH_SLOTS],
+    tss: TaskStateSegment,
+    /// Refers to `tss`, so it can only be created once the block has its
+    /// final address.
+    gdt: Option<Gdt>,
+}
+
+impl PerCpu {
+    pub(crate) fn gdt(&'static self) -> &'static Gdt {
+        self.gdt.as_ref().expect("per-CPU block without GDT")
+    }
+}
+
+/// The block of the bootstrap processor. It is needed before the heap
+/// exists, so it cannot be allocated.
+static mut BOOT_CPU: MaybeUninit<PerCpu> = MaybeUninit::uninit();
+
+/// Set once the bootstrap processor has its block. The other processors
+/// install theirs before running any code that asks for the CPU ID.
+static PER_CPU_READY: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Local APIC ID of every enumerated CPU, indexed by CPU index.
+    static ref APIC_IDS: [AtomicU32; MAX_CPUS] = Default::default();
+}
+
+/// Number of entries used in `APIC_IDS`.
+static ENUMERATED: AtomicUsize = AtomicUsize::new(0);
+/// Number of CPUs that finished starting up, including the bootstrap
+/// processor.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Sets up the per-CPU block of the bootstrap processor. Called by
+/// `gdt::init`.
+pub(crate) fn init() {
+    if PER_CPU_READY.load(Ordering::Relaxed) {
+        return;
+    }
+    unsafe {
+        let block = BOOT_CPU.as_mut_ptr();
+        init_block(block, 0, gdt::boot_ist_stacks());
+        install(block);
+    }
+    PER_CPU_READY.store(true, Ordering::Release);
+}
+
+/// Allocates and sets up the per-CPU block of an application processor,
+/// including its IST stacks. The block is never freed, as CPUs do not go
+/// offline again.
+pub(crate) fn new_block(id: usize) -> Option<*mut PerCpu> {
+    let mut ist_stacks = [VirtAddr::new(0); gdt::IST_STACKS];
+    for ist_stack in ist_stacks.iter_mut() {
+        let stack = KernelStack::allocate()?;
+        *ist_stack = stack.top();
+        core::mem::forget(stack);
+    }
+    let block = Box::leak(Box::new(MaybeUninit::<PerCpu>::uninit())).as_mut_ptr();
+    unsafe { init_block(block, id, ist_stacks) };
+    Some(block)
+}
+
+/// Installs the block from `new_block` on the application processor
+/// calling this. Must come before anything that asks for the CPU ID, which
+/// includes taking any lock.
+///
+/// This function is unsafe because `block` must come from `new_block` and
+/// must not be installed on another CPU.
+pub(crate) unsafe fn init_ap(block: *mut PerCpu) {
+    install(block);
+}
+
+unsafe fn init_block(block: *mut PerCpu, id: usize, ist_stacks: [VirtAddr; gdt::IST_STACKS]) {
+    block.write(PerCpu {
+        self_ptr: block,
+        id,
+        scratch: [0; SCRATCH_SLOTS],
+        tss: gdt::new_tss(ist_stacks),
+        gdt: None,
+    });
+    (*block).gdt = Some(Gdt::new(&(*block).tss));
+}
+
+/// Points the GS base of the current CPU to `block`. The kernel GS base
+/// holds the GS base of user mode, which starts out as zero, and is swapped
+/// with the block by `swapgs` when entering user mode.
+unsafe fn install(block: *const PerCpu) {
+    Msr::new(IA32_GS_BASE).write(block as u64);
+    Msr::new(IA32_KERNEL_GS_BASE).write(0);
+}
+
+/// Returns the per-CPU block of the current CPU.
+///
+/// Must not be called before `gdt::init`.
+pub(crate) fn current() -> &'static PerCpu {
+    let block: *const PerCpu;
+    unsafe {
+        asm!("mov %gs:0, $0" : "=r"(block) ::: "volatile");
+        &*block
+    }
+}
+
+/// Returns the stack pointer the current CPU switches to on interrupts from
+/// user mode.
+///
+/// Must not be called before `gdt::init`.
+pub(crate) fn kernel_stack() -> u64 {
+    let rsp0: u64;
+    // reads `privilege_stack_table[0]` of the TSS at `RSP0_OFFSET`
+    unsafe { asm!("mov %gs:52, $0" : "=r"(rsp0) ::: "volatile") };
+    rsp0
+}
+
+/// Sets the stack pointer the current CPU switches to on interrupts from
+/// user mode.
+///
+/// Must not be called before `gdt::init`.
+pub(crate) fn set_kernel_stack(rsp0: u64) {
+    unsafe { asm!("mov $0, %gs:52" :: "r"(rsp0) : "memory" : "volatile") };
+}
+
+/// Returns the index of the CPU executing this code.
+///
+/// The bootstrap processor is CPU 0, and the application processors are
+/// numbered in the order in which they are listed in the ACPI MADT. The
+/// index is read from the per-CPU block, and is 0 before `gdt::init`.
+pub fn id() -> usize {
+    if !PER_CPU_READY.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let id: usize;
+    // reads `PerCpu::id` at `ID_OFFSET`
+    unsafe { asm!("mov %gs:8, $0" : "=r"(id) ::: "volatile") };
+    id
+}
+
+/// Returns the number of CPUs that are online.
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Returns the local APIC ID of the given CPU, or `None` if there is no CPU
+/// with that index.
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    if cpu < ENUMERATED.load(Ordering::Acquire) {
+        Some(APIC_IDS[cpu].load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Assigns the next CPU index to the CPU with the given local APIC ID.
+/// Returns `None` if `MAX_CPUS` were already enumerated.
+pub(crate) fn add(apic_id: u32) -> Option<usize> {
+    let cpu = ENUMERATED.load(Ordering::Relaxed);
+    if cpu == MAX_CPUS {
+        return None;
+    }
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+    ENUMERATED.store(cpu + 1, Ordering::Release);
+    Some(cpu)
+}
+
+/// Counts the calling application processor as online.
+pub(crate) fn set_online() {
+    ONLINE.fetch_add(1, Ordering::Release);
+}
+
+#[test_case]
+fn test_per_cpu_block() {
+    serial_print!("test_per_cpu_block... ");
+    let block = current();
+    assert_eq!(block as *const PerCpu, block.self_ptr);
+    assert_eq!(id(), 0);
+    assert_eq!(block.id, id());
+
+    let base = block as *const PerCpu as usize;
+    assert_eq!(&block.self_ptr as *const _ as usize - base, SELF_OFFSET);
+    assert_eq!(&block.id as *const _ as usize - base, ID_OFFSET);
+    assert_eq!(&block.scratch as *const _ as usize - base, SCRATCH_OFFSET);
+    assert_eq!(&block.tss as *const _ as usize - base, TSS_OFFSET);
+    serial_println!("[ok]");
+}
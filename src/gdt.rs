@@ -1,46 +1,102 @@
-use lazy_static::lazy_static;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+use crate::cpu;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+/// Number of IST stacks every CPU needs.
+pub const IST_STACKS: usize = 2;
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+/// The GDT of a CPU. It lives in the per-CPU block, next to the TSS it
+/// refers to.
+pub(crate) struct Gdt {
+    table: GlobalDescriptorTable,
+    selectors: Selectors,
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
+impl Gdt {
+    pub(crate) fn new(tss: &'static TaskStateSegment) -> Gdt {
+        let mut table = GlobalDescriptorTable::new();
+        let code_selector = table.add_entry(Descriptor::kernel_code_segment());
+        // user data directly before user code, as `sysret` expects them
+        let user_data_selector = user_selector(table.add_entry(Descriptor::user_data_segment()));
+        let user_code_selector = user_selector(table.add_entry(Descriptor::user_code_segment()));
+        let tss_selector = table.add_entry(Descriptor::tss_segment(tss));
+        Gdt {
+            table,
+            selectors: Selectors { code_selector, user_data_selector, user_code_selector, tss_selector },
+        }
+    }
 
-    GDT.0.load();
-    unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+    /// Returns the code and the stack segment selectors for ring 3.
+    pub(crate) fn user_selectors(&self) -> (SegmentSelector, SegmentSelector) {
+        (self.selectors.user_code_selector, self.selectors.user_data_selector)
     }
+
+    fn load(&'static self) {
+        use x86_64::instructions::segmentation::set_cs;
+        use x86_64::instructions::tables::load_tss;
+
+        self.table.load();
+        unsafe {
+            set_cs(self.selectors.code_selector);
+            load_tss(self.selectors.tss_selector);
+        }
+    }
+}
+
+/// Returns the selector of a ring 3 descriptor with a requested privilege
+/// level of 3, which is needed to load it at CPL 3.
+fn user_selector(selector: SegmentSelector) -> SegmentSelector {
+    SegmentSelector::new(selector.index(), PrivilegeLevel::Ring3)
+}
+
+/// Returns a TSS using the given stacks, indexed by IST index.
+///
+/// Loading a TSS marks its descriptor as busy, so every CPU needs its own,
+/// and with it its own IST stacks. The stack for interrupts from ring 3,
+/// `privilege_stack_table[0]`, is set by `usermode::enter` to the kernel
+/// stack of the thread entering user mode.
+pub(crate) fn new_tss(ist_stacks: [VirtAddr; IST_STACKS]) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stacks[DOUBLE_FAULT_IST_INDEX as usize];
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stacks[NMI_IST_INDEX as usize];
+    tss
+}
+
+/// Returns the IST stacks of the bootstrap processor. They are static, as
+/// they are needed before the kernel stacks can be mapped.
+pub(crate) fn boot_ist_stacks() -> [VirtAddr; IST_STACKS] {
+    const STACK_SIZE: usize = 4096;
+    static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    static mut NMI_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+    let mut stacks = [VirtAddr::new(0); IST_STACKS];
+    stacks[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK }) + STACK_SIZE;
+    stacks[NMI_IST_INDEX as usize] = VirtAddr::from_ptr(unsafe { &NMI_STACK }) + STACK_SIZE;
+    stacks
+}
+
+/// Sets up the per-CPU block of the bootstrap processor and loads its GDT
+/// and TSS.
+pub fn init() {
+    cpu::init();
+    cpu::current().gdt().load();
+}
+
+/// Loads the GDT and TSS of an application processor, once its per-CPU
+/// block was installed by `cpu::init_ap`.
+pub fn init_ap() {
+    cpu::current().gdt().load();
 }
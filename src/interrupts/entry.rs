@@ -0,0 +1,164 @@
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+// Every vector enters the kernel through its own stub. A stub pushes a
+// zero in place of the error code if the CPU does not push one, and then
+// the vector, so that all of them continue in `interrupt_common`. It saves
+// the general-purpose registers below and calls `handle_interrupt` with a
+// pointer to the resulting `TrapFrame`.
+//
+// In user mode, the GS base is the one of the user program and
+// `IA32_KERNEL_GS_BASE` points to the per-CPU block, so `interrupt_common`
+// executes `swapgs` before anything reads `%gs`, and again before `iretq`.
+// Whether the interrupted code ran in user mode is told by the privilege
+// level of its code segment. NMIs, double faults and machine checks can
+// also arrive in kernel mode right before `iretq` to user mode or before
+// the first `swapgs`, so for them the GS base itself is checked instead:
+// the one of user mode is always zero, as user code can only load segments
+// with a zero base. `%rbx` remembers across the call whether to swap back.
+//
+// The CPU aligns the stack to 16 bytes before pushing its frame, and the
+// frame, error code, vector and registers add up to a multiple of 16, so
+// the stack is aligned for the call.
+//
+// The stubs are `STUB_SIZE` bytes apart, so the stub of a vector is found
+// from its number.
+global_asm!(r#"
+.global interrupt_stubs
+.balign 16
+interrupt_stubs:
+    vector = 0
+    .rept 256
+    .balign 16
+    .if !(vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30)
+    pushq $0
+    .endif
+    pushq $vector
+    jmp interrupt_common
+    vector = vector + 1
+    .endr
+
+interrupt_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    cld
+    xor %ebx, %ebx
+    cmpq $2, 120(%rsp)
+    je 1f
+    cmpq $8, 120(%rsp)
+    je 1f
+    cmpq $18, 120(%rsp)
+    je 1f
+    # the privilege level of the saved code segment
+    testb $3, 144(%rsp)
+    jz 3f
+    jmp 2f
+1:
+    mov $0xc0000101, %ecx
+    rdmsr
+    shl $32, %rdx
+    or %rdx, %rax
+    jnz 3f
+2:
+    swapgs
+    mov $1, %ebx
+3:
+    mov %rsp, %rdi
+    call handle_interrupt
+    test %ebx, %ebx
+    jz 4f
+    swapgs
+4:
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    # the vector and the error code
+    add $16, %rsp
+    iretq
+"#);
+
+extern "C" {
+    fn interrupt_stubs();
+}
+
+/// Distance between the entry stubs of two consecutive vectors.
+const STUB_SIZE: usize = 16;
+
+/// Returns the entry stub of `vector`, typed as the handler function that
+/// the IDT entry of the vector expects.
+///
+/// This function is unsafe because the stub only looks like a handler to the
+/// IDT. `F` must be one of the handler function types, and the returned
+/// value must never be called.
+pub(super) unsafe fn stub<F>(vector: u8) -> F {
+    let address = interrupt_stubs as usize + STUB_SIZE * usize::from(vector);
+    core::mem::transmute_copy(&address)
+}
+
+/// The general-purpose registers of the interrupted code, as saved by the
+/// entry stubs.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX={:016x} RSI={:016x} RDI={:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP={:016x} R8 ={:016x} R9 ={:016x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10={:016x} R11={:016x} R12={:016x}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13={:016x} R14={:016x} R15={:016x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// What the entry stubs leave on the stack: the saved registers, the
+/// vector, the error code (zero if there is none) and the frame pushed by
+/// the CPU. Changes to it take effect when the handler returns.
+#[repr(C)]
+pub(crate) struct TrapFrame {
+    pub(crate) registers: Registers,
+    pub(crate) vector: u64,
+    pub(crate) error_code: u64,
+    pub(crate) stack_frame: InterruptStackFrameValue,
+}
@@ -0,0 +1,919 @@
+use crate::sync::{IrqSafeMutex, Mutex};
+use crate::usermode::{self, UserExit};
+use crate::workqueue::{self, Work};
+use crate::{apic, cpu, cpu_local, gdt, println, rcu, serial_println};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use pic8259_simple::ChainedPics;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
+
+mod entry;
+
+pub use self::entry::Registers;
+pub(crate) use self::entry::TrapFrame;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    Rtc = PIC_2_OFFSET,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the PIC line on which this interrupt is raised.
+    pub fn line(self) -> IrqLine {
+        IrqLine::Pic(self.as_u8() - PIC_1_OFFSET)
+    }
+}
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// First IDT vector available to local APIC interrupt sources.
+pub const APIC_VECTOR_BASE: u8 = PIC_2_OFFSET + 8;
+/// Number of IDT vectors available to local APIC interrupt sources.
+pub const APIC_VECTOR_COUNT: u8 = 16;
+
+/// Maximum number of handlers sharing a single line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+const IRQ_VECTOR_COUNT: usize = 16 + APIC_VECTOR_COUNT as usize;
+const LAST_IRQ_VECTOR: u8 = APIC_VECTOR_BASE + APIC_VECTOR_COUNT - 1;
+
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+const PIC_CASCADE_LINE: u8 = 2;
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+/// The lowest-priority line of each PIC, which it reports when an
+/// interrupt request disappears before it could be acknowledged.
+const SPURIOUS_MASTER_LINE: u8 = 7;
+const SPURIOUS_SLAVE_LINE: u8 = 15;
+
+static SPURIOUS_MASTER_IRQS: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_SLAVE_IRQS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+
+        // Sys Interrupts 0-30 - all exceptions enter through the stubs
+        // of `entry`, which save the registers for `handle_interrupt`
+        unsafe {
+            idt.divide_by_zero.set_handler_fn(entry::stub(0));
+            idt.debug.set_handler_fn(entry::stub(1));
+            idt.non_maskable_interrupt.set_handler_fn(entry::stub(2))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.breakpoint.set_handler_fn(entry::stub(3));
+            idt.overflow.set_handler_fn(entry::stub(4));
+            idt.bound_range_exceeded.set_handler_fn(entry::stub(5));
+            idt.invalid_opcode.set_handler_fn(entry::stub(6));
+            idt.device_not_available.set_handler_fn(entry::stub(7));
+            idt.double_fault.set_handler_fn(entry::stub(8))
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss.set_handler_fn(entry::stub(10));
+            idt.segment_not_present.set_handler_fn(entry::stub(11));
+            idt.stack_segment_fault.set_handler_fn(entry::stub(12));
+            idt.general_protection_fault.set_handler_fn(entry::stub(13));
+            idt.page_fault.set_handler_fn(entry::stub(14));
+            idt.x87_floating_point.set_handler_fn(entry::stub(16));
+            idt.alignment_check.set_handler_fn(entry::stub(17));
+            idt.machine_check.set_handler_fn(entry::stub(18));
+            idt.simd_floating_point.set_handler_fn(entry::stub(19));
+            idt.virtualization.set_handler_fn(entry::stub(20));
+            idt.security_exception.set_handler_fn(entry::stub(30));
+        }
+        // PIC Interrupts 0-15 and APIC Interrupts - dispatched to the
+        // handlers registered through `register_irq`
+        for i in 0..IRQ_VECTOR_COUNT {
+            let vector = PIC_1_OFFSET + i as u8;
+            idt[usize::from(vector)].set_handler_fn(unsafe { entry::stub(vector) });
+        }
+        // APIC Spurious Interrupt
+        idt[usize::from(apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(unsafe { entry::stub(apic::SPURIOUS_VECTOR) });
+        // User Mode Exit Gate - the only gate user code may raise
+        idt[usize::from(usermode::EXIT_VECTOR)]
+            .set_handler_fn(unsafe { entry::stub(usermode::EXIT_VECTOR) })
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Clears the PIC mask bit of the given line, so that it is delivered.
+/// Lines on the secondary PIC also unmask the cascade line.
+pub fn unmask_irq(line: u8) {
+    set_irq_masked(line, false);
+}
+
+/// Sets the PIC mask bit of the given line, so that it is no longer
+/// delivered.
+pub fn mask_irq(line: u8) {
+    set_irq_masked(line, true);
+}
+
+fn set_irq_masked(line: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+
+    let update = |mask: u8, bit: u8| {
+        if masked { mask | (1 << bit) } else { mask & !(1 << bit) }
+    };
+    let mut pic_1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut pic_2: Port<u8> = Port::new(PIC_2_DATA);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if line < 8 {
+            let mask = pic_1.read();
+            pic_1.write(update(mask, line));
+        } else {
+            let mask = pic_2.read();
+            pic_2.write(update(mask, line - 8));
+            if !masked {
+                let mask = pic_1.read();
+                pic_1.write(mask & !(1 << PIC_CASCADE_LINE));
+            }
+        }
+    });
+}
+
+///////////////////////////////////////////////
+/// IRQ Registration
+///////////////////////////////////////////////
+
+/// An interrupt line that handlers can be registered for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqLine {
+    /// One of the 16 lines of the chained 8259 PICs.
+    Pic(u8),
+    /// A local APIC vector, counted from `APIC_VECTOR_BASE`.
+    Apic(u8),
+}
+
+impl IrqLine {
+    /// Returns the IDT vector this line is delivered on.
+    pub fn vector(self) -> u8 {
+        match self {
+            IrqLine::Pic(line) => PIC_1_OFFSET + line,
+            IrqLine::Apic(index) => APIC_VECTOR_BASE + index,
+        }
+    }
+
+    fn is_valid(self) -> bool {
+        match self {
+            IrqLine::Pic(line) => line < 16,
+            IrqLine::Apic(index) => index < APIC_VECTOR_COUNT,
+        }
+    }
+
+    fn slot(self) -> usize {
+        usize::from(self.vector() - PIC_1_OFFSET)
+    }
+}
+
+/// Returned by IRQ handlers to tell whether their device raised the
+/// interrupt. Handlers on a shared line must return `NotHandled` if their
+/// device has nothing pending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+/// An IRQ handler. It runs in interrupt context with interrupts disabled,
+/// and the end of interrupt is signalled once all handlers of the line ran.
+pub type IrqHandler = fn() -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line is not a PIC line 0-15 or a valid APIC vector index.
+    InvalidLine,
+    /// `MAX_HANDLERS_PER_LINE` handlers are already registered on the line.
+    LineFull,
+}
+
+/// Identifies a registered handler, used to unregister it again.
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "dropping the handle does not unregister the handler"]
+pub struct IrqHandle {
+    line: IrqLine,
+    id: u64,
+}
+
+impl IrqHandle {
+    pub fn line(&self) -> IrqLine {
+        self.line
+    }
+
+    /// Removes the handler. A PIC line is masked again when its last
+    /// handler is removed. Once this returns, the handler no longer runs.
+    ///
+    /// Must not be called from an IRQ handler.
+    pub fn unregister(self) {
+        let now_empty = IRQ_HANDLERS.update(|handlers| {
+            let slots = &mut handlers[self.line.slot()];
+            for slot in slots.iter_mut() {
+                if let Some(registered) = *slot {
+                    if registered.id == self.id {
+                        *slot = None;
+                    }
+                }
+            }
+            slots.iter().all(Option::is_none)
+        });
+
+        if let (IrqLine::Pic(line), true) = (self.line, now_empty) {
+            mask_irq(line);
+        }
+    }
+}
+
+/// A handler in the table, together with the work it defers to the system
+/// work queue.
+#[derive(Clone, Copy)]
+struct Registered {
+    id: u64,
+    handler: IrqHandler,
+    work: Option<&'static Work>,
+}
+
+type HandlerTable = [[Option<Registered>; MAX_HANDLERS_PER_LINE]; IRQ_VECTOR_COUNT];
+
+/// Two copies of the handler table, of which `dispatch_irq` reads the
+/// published one under RCU without taking a lock. Writers change the other
+/// copy and publish it, so that a table is never changed while it is read.
+///
+/// The tables are static rather than in an `RcuCell`, as handlers are
+/// registered before the heap is set up.
+struct HandlerTables {
+    tables: [UnsafeCell<HandlerTable>; 2],
+    /// Index of the published table.
+    published: AtomicUsize,
+    /// Serialises the writers.
+    writer: Mutex<()>,
+}
+
+// The published table is only read. The other one is only written by the
+// holder of `writer`, after a grace period passed since it was replaced.
+unsafe impl Sync for HandlerTables {}
+
+impl HandlerTables {
+    /// Returns the published table. It stays valid until the read-side
+    /// critical section of `guard` ends.
+    fn read<'a>(&'a self, _guard: &'a rcu::RcuReadGuard) -> &'a HandlerTable {
+        let published = self.published.load(Ordering::Acquire);
+        unsafe { &*self.tables[published].get() }
+    }
+
+    /// Calls `update` on a copy of the published table, publishes the copy
+    /// and waits until no IRQ handler reads the previous table any more.
+    fn update<R>(&self, update: impl FnOnce(&mut HandlerTable) -> R) -> R {
+        let _writer = self.writer.lock();
+        let published = self.published.load(Ordering::Relaxed);
+        let next = 1 - published;
+        let result = unsafe {
+            let table = &mut *self.tables[next].get();
+            *table = *self.tables[published].get();
+            update(table)
+        };
+        self.published.store(next, Ordering::Release);
+        rcu::synchronize_rcu();
+        result
+    }
+}
+
+static IRQ_HANDLERS: HandlerTables = HandlerTables {
+    tables: [
+        UnsafeCell::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_VECTOR_COUNT]),
+        UnsafeCell::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_VECTOR_COUNT]),
+    ],
+    published: AtomicUsize::new(0),
+    writer: Mutex::new(()),
+};
+static NEXT_IRQ_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Registers `handler` for the given line. Several handlers may share one
+/// line; they are called in registration order. PIC lines are unmasked.
+///
+/// Must not be called from an IRQ handler.
+pub fn register_irq(line: IrqLine, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    register(line, handler, None)
+}
+
+/// Registers `handler` for the given line like `register_irq`, and queues
+/// `work` on the system work queue whenever the handler returns `Handled`.
+/// The handler only acknowledges the device, and the rest of the work runs
+/// in process context.
+///
+/// Must not be called before `workqueue::init`.
+pub fn register_threaded_irq(
+    line: IrqLine,
+    handler: IrqHandler,
+    work: &'static Work,
+) -> Result<IrqHandle, IrqError> {
+    // fail here rather than in the first interrupt
+    workqueue::system();
+    register(line, handler, Some(work))
+}
+
+fn register(
+    line: IrqLine,
+    handler: IrqHandler,
+    work: Option<&'static Work>,
+) -> Result<IrqHandle, IrqError> {
+    if !line.is_valid() {
+        return Err(IrqError::InvalidLine);
+    }
+
+    let id = NEXT_IRQ_HANDLE.fetch_add(1, Ordering::Relaxed);
+    IRQ_HANDLERS.update(|handlers| {
+        let free = handlers[line.slot()].iter_mut().find(|slot| slot.is_none());
+        match free {
+            Some(slot) => {
+                *slot = Some(Registered { id, handler, work });
+                Ok(())
+            }
+            None => Err(IrqError::LineFull),
+        }
+    })?;
+
+    if let IrqLine::Pic(line) = line {
+        unmask_irq(line);
+    }
+    Ok(IrqHandle { line, id })
+}
+
+cpu_local! {
+    /// Number of IRQs each CPU is handling.
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Returns `true` while the current CPU runs IRQ handlers.
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.get().load(Ordering::Relaxed) != 0
+}
+
+/// Calls every handler registered for the vector, signals the end of
+/// interrupt to the PIC or local APIC it came from and preempts the running
+/// thread if needed.
+fn dispatch_irq(vector: u8) {
+    if is_spurious_pic_irq(vector) {
+        return;
+    }
+    IRQ_DEPTH.get().fetch_add(1, Ordering::Relaxed);
+
+    let slot = usize::from(vector - PIC_1_OFFSET);
+    let guard = rcu::read_lock();
+    for registered in IRQ_HANDLERS.read(&guard)[slot].iter().flatten() {
+        let handled = (registered.handler)() == IrqReturn::Handled;
+        if let (true, Some(work)) = (handled, registered.work) {
+            workqueue::system().queue(work);
+        }
+    }
+    drop(guard);
+
+    if vector < APIC_VECTOR_BASE {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    } else {
+        apic::end_of_interrupt();
+    }
+    IRQ_DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+
+    // only switch threads once the interrupt is acknowledged, as the
+    // interrupted thread may not run again for a while
+    crate::scheduler::preempt_if_needed();
+}
+
+/// Checks whether an interrupt on IRQ 7 or IRQ 15 is spurious, i.e. not set
+/// in the in-service register of its PIC, and handles it if so.
+///
+/// A spurious IRQ 7 must not be acknowledged at all. A spurious IRQ 15 was
+/// still forwarded by the master through the cascade line, so only the
+/// master receives an end of interrupt.
+fn is_spurious_pic_irq(vector: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let line = vector - PIC_1_OFFSET;
+    let (command, counter) = match line {
+        SPURIOUS_MASTER_LINE => (PIC_1_COMMAND, &SPURIOUS_MASTER_IRQS),
+        SPURIOUS_SLAVE_LINE => (PIC_2_COMMAND, &SPURIOUS_SLAVE_IRQS),
+        _ => return false,
+    };
+
+    let _pics = PICS.lock();
+    let mut command: Port<u8> = Port::new(command);
+    let in_service = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    if in_service & (1 << (line % 8)) != 0 {
+        return false;
+    }
+
+    counter.fetch_add(1, Ordering::Relaxed);
+    if line == SPURIOUS_SLAVE_LINE {
+        let mut master: Port<u8> = Port::new(PIC_1_COMMAND);
+        unsafe { master.write(PIC_EOI) };
+    }
+    true
+}
+
+/// Returns the number of spurious interrupts reported on IRQ 7 and IRQ 15.
+pub fn spurious_pic_irqs() -> (u64, u64) {
+    (
+        SPURIOUS_MASTER_IRQS.load(Ordering::Relaxed),
+        SPURIOUS_SLAVE_IRQS.load(Ordering::Relaxed),
+    )
+}
+
+///////////////////////////////////////////////
+/// Statistics
+///////////////////////////////////////////////
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error", "Debug", "Non-maskable Interrupt", "Breakpoint",
+    "Overflow", "Bound Range Exceeded", "Invalid Opcode", "Device Not Available",
+    "Double Fault", "Coprocessor Segment Overrun", "Invalid TSS", "Segment Not Present",
+    "Stack-Segment Fault", "General Protection Fault", "Page Fault", "Reserved",
+    "x87 Floating-Point", "Alignment Check", "Machine Check", "SIMD Floating-Point",
+    "Virtualization", "Reserved", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Security Exception", "Reserved",
+];
+
+lazy_static! {
+    /// Number of times each IDT vector was raised, per CPU.
+    static ref INTERRUPT_COUNTS: [[AtomicU64; 256]; cpu::MAX_CPUS] = {
+        // an all-zero AtomicU64 is a valid counter at zero
+        unsafe { core::mem::zeroed() }
+    };
+}
+
+fn record_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[cpu::id()][usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how often `vector` was raised on the given CPU.
+pub fn interrupt_count(cpu: usize, vector: u8) -> u64 {
+    INTERRUPT_COUNTS[cpu][usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Returns how often `vector` was raised, summed over all CPUs.
+pub fn total_interrupt_count(vector: u8) -> u64 {
+    (0..cpu::MAX_CPUS).map(|cpu| interrupt_count(cpu, vector)).sum()
+}
+
+/// Returns a human readable name for an IDT vector.
+pub fn vector_name(vector: u8) -> &'static str {
+    const PIC_LINES: [&str; 16] = [
+        "IRQ0 timer", "IRQ1 keyboard", "IRQ2 cascade", "IRQ3", "IRQ4", "IRQ5",
+        "IRQ6", "IRQ7", "IRQ8 rtc", "IRQ9", "IRQ10", "IRQ11", "IRQ12", "IRQ13",
+        "IRQ14", "IRQ15",
+    ];
+
+    match vector {
+        0..=31 => EXCEPTION_NAMES[usize::from(vector)],
+        v if v >= PIC_1_OFFSET && v < APIC_VECTOR_BASE => PIC_LINES[usize::from(v - PIC_1_OFFSET)],
+        v if v >= APIC_VECTOR_BASE && v < APIC_VECTOR_BASE + APIC_VECTOR_COUNT => "APIC",
+        apic::SPURIOUS_VECTOR => "APIC spurious",
+        usermode::EXIT_VECTOR => "user mode exit",
+        _ => "unassigned",
+    }
+}
+
+/// Prints the counters of every vector that was raised at least once to the
+/// serial port, in the style of `/proc/interrupts`.
+pub fn print_interrupt_stats() {
+    use core::fmt::Write;
+
+    let mut header = LineBuffer::default();
+    let _ = write!(header, "     ");
+    for cpu in 0..cpu::MAX_CPUS {
+        if (0..=255).any(|vector| interrupt_count(cpu, vector) != 0) {
+            // `format_args!` ignores the width, so format the label first
+            let mut label = LineBuffer::default();
+            let _ = write!(label, "CPU{}", cpu);
+            let _ = write!(header, " {:>10}", label.as_str());
+        }
+    }
+    serial_println!("{}", header.as_str());
+
+    for vector in 0..=255u8 {
+        if total_interrupt_count(vector) == 0 {
+            continue;
+        }
+        let mut line = LineBuffer::default();
+        let _ = write!(line, "{:>4}:", vector);
+        for cpu in 0..cpu::MAX_CPUS {
+            if (0..=255).any(|vector| interrupt_count(cpu, vector) != 0) {
+                let _ = write!(line, " {:>10}", interrupt_count(cpu, vector));
+            }
+        }
+        serial_println!("{}   {}", line.as_str(), vector_name(vector));
+    }
+
+    let (master, slave) = spurious_pic_irqs();
+    serial_println!(" SPU: {:>10}   spurious IRQ7, {} spurious IRQ15", master, slave);
+}
+
+/// A fixed-size line buffer, so that printing the statistics does not need
+/// the heap.
+struct LineBuffer {
+    buffer: [u8; 128],
+    len: usize,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        LineBuffer { buffer: [0; 128], len: 0 }
+    }
+}
+
+impl LineBuffer {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////
+/// Interrupt Handlers
+///////////////////////////////////////////////
+
+/// Writes to both the serial port and the screen, so that the dump of a
+/// fatal exception reaches the host even if the screen is not visible.
+macro_rules! dump {
+    ($($arg:tt)*) => {
+        $crate::serial_println!($($arg)*);
+        println!($($arg)*);
+    };
+}
+
+/// Called by the entry stubs with the state of the interrupted code, which
+/// is resumed once this returns.
+#[no_mangle]
+extern "C" fn handle_interrupt(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    record_interrupt(vector);
+    match vector {
+        1 => debug_handler(frame),
+        2 => nmi_handler(frame),
+        3 => breakpoint_handler(frame),
+        14 => page_fault_handler(frame),
+        0..=31 => fatal_exception(vector, frame),
+        PIC_1_OFFSET..=LAST_IRQ_VECTOR => dispatch_irq(vector),
+        // spurious APIC interrupts must not be acknowledged
+        apic::SPURIOUS_VECTOR => {}
+        usermode::EXIT_VECTOR => usermode::exit_gate(frame),
+        _ => panic!("unexpected interrupt vector {}", vector),
+    }
+}
+
+fn debug_handler(frame: &TrapFrame) {
+    let dr6: u64;
+    unsafe { asm!("mov %dr6, $0" : "=r"(dr6)) };
+    println!("EXCEPTION: DEBUG (DR6 = {:#x})\n{:#?}", dr6, frame.stack_frame);
+}
+
+fn nmi_handler(frame: &TrapFrame) {
+    if crate::watchdog::handle_nmi(frame) {
+        return;
+    }
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", frame.stack_frame);
+}
+
+fn breakpoint_handler(frame: &TrapFrame) {
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame);
+}
+
+fn page_fault_handler(frame: &TrapFrame) {
+    use crate::memory;
+    use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read();
+    let stack_frame = &frame.stack_frame;
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if usermode::from_user_mode(stack_frame) {
+        usermode::leave(UserExit::PageFault {
+            address,
+            error_code,
+            instruction_pointer: stack_frame.instruction_pointer,
+        });
+    }
+    let class = memory::classify_address(address.as_u64(), stack_frame.stack_pointer.as_u64());
+    let walk = memory::walk_page_tables(address);
+
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch from"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write to"
+    } else {
+        "read from"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in page table"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+
+    dump!("PAGE FAULT: {} {} {:#x} ({}, {}) by {} code at {:#x}",
+        access, class.description(), address.as_u64(), cause,
+        walk_summary(&walk), mode, stack_frame.instruction_pointer.as_u64());
+    print_exception("PAGE FAULT (#PF)", frame, ErrorCode::PageFault(error_code));
+    for (i, entry) in walk.entries.iter().enumerate() {
+        if let Some((addr, flags)) = entry {
+            dump!("  P{} entry: {:#x} {:?}", 4 - i, addr.as_u64(), flags);
+        }
+    }
+    panic!("EXCEPTION: PAGE FAULT (#PF)");
+}
+
+/// Describes where the page table walk for a faulting address stopped.
+fn walk_summary(walk: &crate::memory::PageWalk) -> &'static str {
+    match walk.missing_level {
+        Some(4) => "P4 entry missing",
+        Some(3) => "P3 entry missing",
+        Some(2) => "P2 entry missing",
+        Some(_) => "P1 entry missing",
+        None if walk.is_mapped() => "mapped",
+        None => "page tables unavailable",
+    }
+}
+
+///////////////////////////////////////////////
+/// Exception Reporting
+///////////////////////////////////////////////
+
+/// The error code pushed by an exception, decoded where its format is known.
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    None,
+    Code(u64),
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+}
+
+/// The error code of #TS, #NP, #SS and #GP, which refers to the segment
+/// selector that caused the exception (or is zero if there is none).
+#[derive(Debug, Clone, Copy)]
+struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    /// The exception was caused by an event external to the program.
+    fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    fn table(self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b01 | 0b11 => "IDT",
+            _ => "LDT",
+        }
+    }
+
+    fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (no selector)", self.0);
+        }
+        write!(f, "{:#x} ({} index {}{})", self.0, self.table(), self.index(),
+            if self.external() { ", external" } else { "" })
+    }
+}
+
+impl ErrorCode {
+    /// Decodes the error code pushed for `vector`. The entry stubs push a
+    /// zero for exceptions without one.
+    fn decode(vector: u8, code: u64) -> ErrorCode {
+        match vector {
+            8 | 17 | 21 | 29 | 30 => ErrorCode::Code(code),
+            10..=13 => ErrorCode::Selector(SelectorErrorCode(code)),
+            14 => ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code)),
+            _ => ErrorCode::None,
+        }
+    }
+
+    fn raw(self) -> Option<u64> {
+        match self {
+            ErrorCode::None => None,
+            ErrorCode::Code(code) => Some(code),
+            ErrorCode::Selector(selector) => Some(selector.0),
+            ErrorCode::PageFault(code) => Some(code.bits()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Code(code) => write!(f, "{:#x}", code),
+            ErrorCode::Selector(selector) => write!(f, "{}", selector),
+            ErrorCode::PageFault(code) => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Returns the name a fatal exception is reported with.
+fn fatal_exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR (#DE)",
+        4 => "OVERFLOW (#OF)",
+        5 => "BOUND RANGE EXCEEDED (#BR)",
+        6 => "INVALID OPCODE (#UD)",
+        7 => "DEVICE NOT AVAILABLE (#NM)",
+        8 => "DOUBLE FAULT (#DF)",
+        10 => "INVALID TSS (#TS)",
+        11 => "SEGMENT NOT PRESENT (#NP)",
+        12 => "STACK-SEGMENT FAULT (#SS)",
+        13 => "GENERAL PROTECTION FAULT (#GP)",
+        14 => "PAGE FAULT (#PF)",
+        16 => "x87 FLOATING-POINT (#MF)",
+        17 => "ALIGNMENT CHECK (#AC)",
+        18 => "MACHINE CHECK (#MC)",
+        19 => "SIMD FLOATING-POINT (#XM)",
+        20 => "VIRTUALIZATION (#VE)",
+        30 => "SECURITY EXCEPTION (#SX)",
+        _ => "RESERVED",
+    }
+}
+
+/// The common path of every fatal exception: prints the exception and
+/// panics. Exceptions raised in user mode return to the kernel instead,
+/// except for double faults, which are never caused by user code alone.
+fn fatal_exception(vector: u8, frame: &TrapFrame) -> ! {
+    let name = fatal_exception_name(vector);
+    let error_code = ErrorCode::decode(vector, frame.error_code);
+    if vector != 8 && usermode::from_user_mode(&frame.stack_frame) {
+        usermode::leave(UserExit::Exception {
+            vector,
+            error_code: error_code.raw(),
+            instruction_pointer: frame.stack_frame.instruction_pointer,
+        });
+    }
+    print_exception(name, frame, error_code);
+    panic!("EXCEPTION: {}", name);
+}
+
+/// Prints the exception, its decoded error code, the control registers, the
+/// general-purpose registers and the interrupted stack frame.
+fn print_exception(name: &str, frame: &TrapFrame, error_code: ErrorCode) {
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        asm!("mov %cr0, $0" : "=r"(cr0));
+        asm!("mov %cr2, $0" : "=r"(cr2));
+        asm!("mov %cr3, $0" : "=r"(cr3));
+        asm!("mov %cr4, $0" : "=r"(cr4));
+    }
+
+    dump!("EXCEPTION: {}", name);
+    dump!("Error Code: {}", error_code);
+    dump!("CR0={:#018x} CR2={:#018x}", cr0, cr2);
+    dump!("CR3={:#018x} CR4={:#018x}", cr3, cr4);
+    dump!("{}", frame.registers);
+    dump!("{:#?}", frame.stack_frame);
+}
+
+///////////////////////////////////////////////
+/// Tests
+///////////////////////////////////////////////
+#[test_case]
+fn test_breakpoint_exception() {
+    serial_print!("test_breakpoint_exception...");
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_shared_irq_line() {
+    use core::sync::atomic::AtomicUsize;
+
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+
+    fn first() -> IrqReturn {
+        FIRST.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::NotHandled
+    }
+
+    fn second() -> IrqReturn {
+        SECOND.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+
+    serial_print!("test_shared_irq_line...");
+    let line = IrqLine::Apic(0);
+    let first_handle = register_irq(line, first).expect("register failed");
+    let second_handle = register_irq(line, second).expect("register failed");
+
+    unsafe { asm!("int $$0x30" :::: "volatile") };
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 1);
+
+    first_handle.unregister();
+    unsafe { asm!("int $$0x30" :::: "volatile") };
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 2);
+
+    second_handle.unregister();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_register_irq_errors() {
+    fn handler() -> IrqReturn {
+        IrqReturn::Handled
+    }
+
+    serial_print!("test_register_irq_errors...");
+    assert_eq!(register_irq(IrqLine::Pic(16), handler), Err(IrqError::InvalidLine));
+    assert_eq!(register_irq(IrqLine::Apic(APIC_VECTOR_COUNT), handler), Err(IrqError::InvalidLine));
+
+    let line = IrqLine::Apic(1);
+    let mut handles = [None, None, None, None];
+    for handle in handles.iter_mut() {
+        *handle = Some(register_irq(line, handler).expect("register failed"));
+    }
+    assert_eq!(register_irq(line, handler), Err(IrqError::LineFull));
+    for handle in handles.iter_mut() {
+        handle.take().unwrap().unregister();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    serial_print!("test_selector_error_code...");
+    let gdt_selector = SelectorErrorCode(0x18);
+    assert_eq!((gdt_selector.table(), gdt_selector.index()), ("GDT", 3));
+    assert!(!gdt_selector.external());
+    let idt_vector = SelectorErrorCode((13 << 3) | 0b010 | 1);
+    assert_eq!((idt_vector.table(), idt_vector.index()), ("IDT", 13));
+    assert!(idt_vector.external());
+    assert_eq!(SelectorErrorCode(0b100).table(), "LDT");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_interrupt_counts() {
+    serial_print!("test_interrupt_counts...");
+    let breakpoints = total_interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(total_interrupt_count(3), breakpoints + 1);
+    assert_eq!(vector_name(3), "Breakpoint");
+    assert_eq!(vector_name(33), "IRQ1 keyboard");
+    print_interrupt_stats();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_spurious_pic_irqs() {
+    serial_print!("test_spurious_pic_irqs...");
+    let (master, slave) = spurious_pic_irqs();
+    // a software interrupt on the IRQ 7 and IRQ 15 vectors is not in
+    // service at the PIC, so it looks exactly like a spurious interrupt
+    unsafe { asm!("int $$0x27" :::: "volatile") };
+    assert_eq!(spurious_pic_irqs(), (master + 1, slave));
+    unsafe { asm!("int $$0x2f" :::: "volatile") };
+    assert_eq!(spurious_pic_irqs(), (master + 1, slave + 1));
+    serial_println!("[ok]");
+}
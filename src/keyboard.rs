@@ -0,0 +1,198 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::bottom_half::{self, BottomHalf};
+use crate::interrupts::{self as irq, InterruptIndex, IrqReturn};
+use crate::sync::IrqSafeMutex;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Capacity of the scancode queue. Must be a power of two.
+const QUEUE_CAPACITY: usize = 128;
+
+/// A fixed-capacity, lock-free queue with a single producer and a single
+/// consumer: the keyboard IRQ handler and the bottom half, or the bottom
+/// half and a subscriber.
+struct ScancodeQueue {
+    buffer: UnsafeCell<[u8; QUEUE_CAPACITY]>,
+    /// Total number of scancodes pushed, only written by the producer.
+    head: AtomicUsize,
+    /// Total number of scancodes popped, only written by the consumer.
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+// The producer only writes the slot at `head` and the consumer only reads
+// the slot at `tail`, and a slot is never both at once.
+unsafe impl Sync for ScancodeQueue {}
+
+impl ScancodeQueue {
+    const fn new() -> ScancodeQueue {
+        ScancodeQueue {
+            buffer: UnsafeCell::new([0; QUEUE_CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Appends a scancode. Must only be called by the producer. If the
+    /// queue is full, the scancode is dropped and counted.
+    fn push(&self, scancode: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == QUEUE_CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*self.buffer.get())[head % QUEUE_CAPACITY] = scancode };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Removes the oldest scancode. Must only be called by the consumer.
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let scancode = unsafe { (*self.buffer.get())[tail % QUEUE_CAPACITY] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(scancode)
+    }
+}
+
+static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+static BOTTOM_HALF: Once<BottomHalf> = Once::new();
+/// The subscribers, each of which receives every scancode.
+static SUBSCRIBERS: IrqSafeMutex<Vec<Arc<Subscriber>>> = IrqSafeMutex::new(Vec::new());
+
+/// The scancodes handed to one subscriber, and the task waiting for them.
+struct Subscriber {
+    scancodes: ScancodeQueue,
+    waker: AtomicWaker,
+}
+
+/// Receives every scancode from the time it was created. Scancodes arriving
+/// while its queue is full are dropped for this subscription only.
+/// Dropping it unsubscribes.
+pub struct Subscription {
+    subscriber: Arc<Subscriber>,
+}
+
+impl Subscription {
+    /// Returns the oldest received scancode, or registers the task of
+    /// `context` to be woken when the next one arrives.
+    pub fn poll_scancode(&mut self, context: &mut Context) -> Poll<u8> {
+        let subscriber = &self.subscriber;
+        if let Some(scancode) = subscriber.scancodes.pop() {
+            return Poll::Ready(scancode);
+        }
+        subscriber.waker.register(context.waker());
+        // a scancode may have arrived before the waker was registered
+        match subscriber.scancodes.pop() {
+            Some(scancode) => {
+                subscriber.waker.take();
+                Poll::Ready(scancode)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    /// Returns the number of scancodes dropped because this subscription
+    /// was not read fast enough.
+    pub fn dropped(&self) -> u64 {
+        self.subscriber.scancodes.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS
+            .lock()
+            .retain(|subscriber| !Arc::ptr_eq(subscriber, &self.subscriber));
+    }
+}
+
+/// Registers the keyboard IRQ handler and the bottom half that hands the
+/// received scancodes to the subscribers.
+pub fn init() {
+    BOTTOM_HALF.call_once(|| {
+        bottom_half::register(process_scancodes).expect("no free bottom half")
+    });
+    irq::register_irq(InterruptIndex::Keyboard.line(), keyboard_irq)
+        .expect("failed to register keyboard handler");
+}
+
+/// Returns a subscription that receives every scancode from now on.
+pub fn subscribe() -> Subscription {
+    let subscriber = Arc::new(Subscriber {
+        scancodes: ScancodeQueue::new(),
+        waker: AtomicWaker::new(),
+    });
+    SUBSCRIBERS.lock().push(subscriber.clone());
+    Subscription { subscriber }
+}
+
+/// Returns the number of scancodes dropped because the queue was full.
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.dropped.load(Ordering::Relaxed)
+}
+
+/// The keyboard IRQ handler. It only reads the scancode and defers handing
+/// it out to the bottom half.
+fn keyboard_irq() -> IrqReturn {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    SCANCODES.push(scancode);
+    if let Some(bottom_half) = BOTTOM_HALF.r#try() {
+        bottom_half.raise();
+    }
+    IrqReturn::Handled
+}
+
+/// Copies the queued scancodes to every subscriber and wakes their tasks.
+fn process_scancodes() {
+    // holding the lock keeps every queue to a single producer, and the
+    // scancode queue to a single consumer
+    let subscribers = SUBSCRIBERS.lock();
+    let mut received = false;
+    while let Some(scancode) = SCANCODES.pop() {
+        received = true;
+        for subscriber in subscribers.iter() {
+            // a full queue counts the scancode as dropped
+            subscriber.scancodes.push(scancode);
+        }
+    }
+    if received {
+        for subscriber in subscribers.iter() {
+            subscriber.waker.wake();
+        }
+    }
+}
+
+#[test_case]
+fn test_scancode_queue() {
+    serial_print!("test_scancode_queue... ");
+    let queue = ScancodeQueue::new();
+    assert_eq!(queue.pop(), None);
+    for i in 0..QUEUE_CAPACITY {
+        assert!(queue.push(i as u8));
+    }
+    assert!(!queue.push(0xff));
+    assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+    for i in 0..QUEUE_CAPACITY {
+        assert_eq!(queue.pop(), Some(i as u8));
+    }
+    assert_eq!(queue.pop(), None);
+    serial_println!("[ok]");
+}
@@ -1,28 +1,55 @@
 #![no_std]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(global_asm)]
 #![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
 #![feature(custom_test_frameworks)]
+#![feature(track_caller)]
+#![cfg_attr(feature = "lockdep", feature(const_caller_location))]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod bottom_half;
+pub mod channel;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
+pub mod rcu;
+pub mod rtc;
+pub mod scheduler;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod timer;
+pub mod tlb;
+pub mod usermode;
 pub mod vga_buffer;
+pub mod watchdog;
+pub mod workqueue;
 
 use core::panic::PanicInfo;
 #[cfg(test)]
 use bootloader::{BootInfo, entry_point};
-use linked_list_allocator::LockedHeap;
+use linked_list_allocator::Heap;
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    time::init();
+    timer::init();
+    rtc::init();
+    keyboard::init();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
 }
@@ -48,6 +75,34 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Enables interrupts and halts the CPU until the next interrupt.
+///
+/// `sti` only takes effect after the following instruction, so no interrupt
+/// can arrive between the two and be missed by the `hlt`.
+pub fn enable_interrupts_and_hlt() {
+    unsafe {
+        asm!("sti; hlt" :::: "volatile");
+    }
+}
+
+/// Runs the bottom halves raised by interrupt handlers, and waits for the
+/// next interrupt whenever there is nothing left to do.
+pub fn idle_loop() -> ! {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        watchdog::touch();
+        bottom_half::run_pending();
+
+        interrupts::disable();
+        if bottom_half::has_pending() {
+            interrupts::enable();
+        } else {
+            scheduler::wait_for_interrupt();
+        }
+    }
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
@@ -80,8 +135,10 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+/// The kernel heap. Its lock disables interrupts, so that neither an
+/// interrupt handler nor a preempted thread can deadlock on it.
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: sync::IrqSafeMutex<Heap> = sync::IrqSafeMutex::new(Heap::empty());
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
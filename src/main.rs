@@ -10,6 +10,7 @@ use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 use bootloader::{BootInfo,  entry_point};
 use core::panic::PanicInfo;
 use curi_os::println;
+use curi_os::task::{executor::Executor, keyboard, Task};
 
 /// This function is called on panic.
 #[cfg(not(test))]
@@ -29,7 +30,16 @@ entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use curi_os::allocator;
+    use curi_os::apic;
     use curi_os::memory;
+    use curi_os::rtc;
+    use curi_os::scheduler;
+    use curi_os::smp;
+    use curi_os::thread;
+    use curi_os::time;
+    use curi_os::watchdog;
+    use curi_os::workqueue;
+    use core::time::Duration;
     use x86_64::VirtAddr;
     use x86_64::structures::paging::{Page};
 
@@ -43,6 +53,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+    workqueue::init();
+
+    let source = if time::calibrate_with_hpet() { "HPET" } else { "PIT" };
+    if !apic::init() {
+        println!("no local APIC found");
+    }
+    println!("{} CPUs online", smp::init());
+    if !watchdog::init() {
+        println!("NMI watchdog unavailable, only soft lockups are detected");
+    }
+    println!("Current time: {} UTC", rtc::read());
+    println!("TSC frequency: {} Hz ({}, invariant: {})",
+        time::tsc_frequency(), source, time::has_invariant_tsc());
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -65,6 +90,26 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
+    // the worker sleeps while the boot thread goes on
+    thread::spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        println!("hello from kernel thread {}", thread::current().as_u64());
+        scheduler::dump();
+    });
+
     println!("It didn't crash!");
-    curi_os::hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
@@ -1,10 +1,27 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::structures::paging::{
-    Page, Size4KiB, Mapper, FrameAllocator,
-    PageTable, PhysFrame, MapperAllSizes, MappedPageTable
+    Page, Size4KiB, Mapper, FrameAllocator, mapper::{FlagUpdateError, MapToError, MapperFlush},
+    PageTable, PageTableFlags, PhysFrame, MapperAllSizes, MappedPageTable
 };
 
+use crate::sync::IrqSafeMutex;
+use crate::tlb;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Virtual address at which the bootloader mapped the complete physical
+/// memory. Set once by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The frame allocator used after boot. It also serialises all changes to
+/// the page tables.
+static FRAME_ALLOCATOR: IrqSafeMutex<Option<BootInfoFrameAllocator>> = IrqSafeMutex::new(None);
+
 /// Initialise a new MappedPageTable
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -12,6 +29,11 @@ use x86_64::structures::paging::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behaviour).
 pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    active_mapper(physical_memory_offset)
+}
+
+unsafe fn active_mapper(physical_memory_offset: u64) -> impl MapperAllSizes {
     let level_4_table = active_level_4_table(physical_memory_offset);
     let phys_to_virt = move |frame: PhysFrame| -> *mut PageTable {
         let phys = frame.start_address().as_u64();
@@ -21,6 +43,248 @@ pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
     MappedPageTable::new(level_4_table, phys_to_virt)
 }
 
+/// Hands the frame allocator over to the kernel once booting is done, so
+/// that `map_pages` can be used.
+///
+/// The page tables are only modified through `map_pages` and `unmap_pages`
+/// afterwards; the mapper returned by `init` must no longer be used.
+pub fn init_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Maps `count` pages starting at `start` to newly allocated frames.
+///
+/// The pages were not mapped before, and the CPUs do not cache missing
+/// translations, so no TLB shootdown is needed. Pages mapped with
+/// `USER_ACCESSIBLE` also get it set in the table entries above them, as
+/// the CPU checks it at every level.
+pub fn map_pages(start: Page, count: u64, flags: PageTableFlags) -> Result<(), MapToError> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator
+        .as_mut()
+        .expect("frame allocator not initialised, call memory::init_frame_allocator first");
+    let mut mapper = unsafe { active_mapper(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)) };
+
+    for page in Page::range(start, start + count) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            allow_user_access(page.start_address());
+        }
+    }
+    Ok(())
+}
+
+/// Sets `USER_ACCESSIBLE` in the level 4 to level 2 entries translating
+/// `addr`, which the mapper creates without it. The leaf entries below
+/// them keep their own flags, so kernel pages they map stay protected.
+fn allow_user_access(addr: VirtAddr) {
+    use x86_64::registers::control::Cr3;
+
+    let (mut frame, _) = Cr3::read();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    for index in indices.iter() {
+        let table: *mut PageTable = match phys_to_virt(frame.start_address()) {
+            Some(virt) => virt.as_mut_ptr(),
+            None => return,
+        };
+        let entry = unsafe { &mut (*table)[*index] };
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    // also drops cached table entries still without the flag
+    x86_64::instructions::tlb::flush(addr);
+}
+
+/// Maps the page with the same address as `frame` to it, as needed for
+/// code that runs before paging is enabled. Succeeds without changes if the
+/// page is already identity mapped.
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator
+        .as_mut()
+        .expect("frame allocator not initialised, call memory::init_frame_allocator first");
+    let mut mapper = unsafe { active_mapper(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)) };
+
+    let addr = frame.start_address();
+    match mapper.translate_addr(VirtAddr::new(addr.as_u64())) {
+        Some(mapped) if mapped == addr => Ok(()),
+        Some(_) => Err(MapToError::PageAlreadyMapped),
+        None => {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr.as_u64()));
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+            Ok(())
+        }
+    }
+}
+
+/// Returns the type of the memory map region containing `addr`, or `None`
+/// if it is not covered by the memory map or the frame allocator has not
+/// been handed over yet.
+pub fn region_type(addr: PhysAddr) -> Option<MemoryRegionType> {
+    let frame_allocator = FRAME_ALLOCATOR.lock();
+    let memory_map = frame_allocator.as_ref()?.memory_map;
+    memory_map
+        .iter()
+        .find(|r| r.range.start_addr() <= addr.as_u64() && addr.as_u64() < r.range.end_addr())
+        .map(|r| r.region_type)
+}
+
+/// Removes the mappings of `count` pages starting at `start`. Pages that
+/// are not mapped are skipped. The stale translations are flushed from the
+/// TLBs of all CPUs.
+///
+/// The frames are not reused, as the boot frame allocator cannot free them.
+pub fn unmap_pages(start: Page, count: u64) {
+    {
+        let _frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut mapper = unsafe { active_mapper(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)) };
+
+        for page in Page::<Size4KiB>::range(start, start + count) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.ignore();
+            }
+        }
+    }
+    // other CPUs may spin on the page table lock with interrupts disabled,
+    // so the shootdown waits until it is released
+    tlb::flush(start, count);
+}
+
+/// Changes the flags of `count` mapped pages starting at `start`, for
+/// example to make them read-only. The stale translations are flushed from
+/// the TLBs of all CPUs.
+pub fn protect_pages(start: Page, count: u64, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    let result = {
+        let _frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut mapper = unsafe { active_mapper(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)) };
+
+        Page::<Size4KiB>::range(start, start + count)
+            .map(|page| mapper.update_flags(page, flags).map(MapperFlush::ignore))
+            .collect::<Result<(), FlagUpdateError>>()
+    };
+    // pages before a failing one were changed already
+    tlb::flush(start, count);
+    result
+}
+
+/// Returns the virtual address through which the given physical address can
+/// be accessed, or `None` if `init` has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(addr.as_u64() + offset)),
+    }
+}
+
+/// The entries visited while translating an address through the active page
+/// tables, from the level 4 table down.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageWalk {
+    /// The entry read at each level, indexed by `4 - level`.
+    pub entries: [Option<(PhysAddr, PageTableFlags)>; 4],
+    /// The level whose entry is not present, if the walk stopped early.
+    pub missing_level: Option<u8>,
+}
+
+impl PageWalk {
+    /// Returns `true` if the walk reached a present leaf entry.
+    pub fn is_mapped(&self) -> bool {
+        self.missing_level.is_none() && self.entries.iter().any(Option::is_some)
+    }
+}
+
+/// Walks the active page tables for `addr` without modifying them.
+///
+/// Returns an empty walk if the physical memory mapping is not set up yet.
+pub fn walk_page_tables(addr: VirtAddr) -> PageWalk {
+    use x86_64::registers::control::Cr3;
+
+    let mut walk = PageWalk::default();
+    let (mut frame, _) = Cr3::read();
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+
+    for (i, index) in indices.iter().enumerate() {
+        let level = 4 - i as u8;
+        let table_ptr: *const PageTable = match phys_to_virt(frame.start_address()) {
+            Some(virt) => virt.as_ptr(),
+            None => return walk,
+        };
+        let entry = unsafe { &(*table_ptr)[*index] };
+        let flags = entry.flags();
+        walk.entries[i] = Some((entry.addr(), flags));
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            walk.missing_level = Some(level);
+            break;
+        }
+        if level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            break;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    walk
+}
+
+/// The kind of region a faulting address falls into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressClass {
+    /// The first page, usually a dereferenced null pointer.
+    NullPage,
+    /// The unmapped page directly below or above the kernel heap.
+    HeapGuard,
+    /// The page directly below the current stack, usually an overflow.
+    StackGuard,
+    /// An address that is not in canonical form. Never reported for a page
+    /// fault, since a non-canonical access raises #GP or #SS instead, so it
+    /// only applies to other callers of `classify_address`.
+    NonCanonical,
+    /// An unmapped address in the upper (kernel) half.
+    KernelSpace,
+    /// Any other address.
+    Other,
+}
+
+impl AddressClass {
+    pub fn description(self) -> &'static str {
+        match self {
+            AddressClass::NullPage => "null page",
+            AddressClass::HeapGuard => "heap guard page",
+            AddressClass::StackGuard => "stack guard page",
+            AddressClass::NonCanonical => "non-canonical address",
+            AddressClass::KernelSpace => "unmapped kernel space",
+            AddressClass::Other => "unclassified address",
+        }
+    }
+}
+
+/// Classifies `addr` given the stack pointer at the time of the access.
+pub fn classify_address(addr: u64, stack_pointer: u64) -> AddressClass {
+    use crate::allocator::{HEAP_SIZE, HEAP_START};
+
+    let heap_start = HEAP_START as u64;
+    let heap_end = heap_start + HEAP_SIZE as u64;
+    let stack_page = stack_pointer & !(PAGE_SIZE - 1);
+
+    if addr < PAGE_SIZE {
+        AddressClass::NullPage
+    } else if VirtAddr::try_new(addr).is_err() {
+        AddressClass::NonCanonical
+    } else if (addr < heap_start && addr >= heap_start - PAGE_SIZE)
+        || (addr >= heap_end && addr < heap_end + PAGE_SIZE)
+    {
+        AddressClass::HeapGuard
+    } else if addr < stack_page && addr >= stack_page - PAGE_SIZE {
+        AddressClass::StackGuard
+    } else if addr >= 0xffff_8000_0000_0000 {
+        AddressClass::KernelSpace
+    } else {
+        AddressClass::Other
+    }
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: u64)
     -> &'static mut PageTable
 {
@@ -100,3 +364,22 @@ pub fn create_example_mapping(
     };
     map_to_result.expect("map_to failed").flush();
 }
+
+#[test_case]
+fn test_classify_address() {
+    use crate::allocator::{HEAP_SIZE, HEAP_START};
+
+    serial_print!("test_classify_address... ");
+    let stack_pointer = 0x_5000_1234;
+    let heap_end = (HEAP_START + HEAP_SIZE) as u64;
+    let classify = |addr| classify_address(addr, stack_pointer);
+
+    assert_eq!(classify(0x8), AddressClass::NullPage);
+    assert_eq!(classify(HEAP_START as u64 - 8), AddressClass::HeapGuard);
+    assert_eq!(classify(heap_end), AddressClass::HeapGuard);
+    assert_eq!(classify(0x_4fff_fff8), AddressClass::StackGuard);
+    assert_eq!(classify(0x_8000_0000_0000), AddressClass::NonCanonical);
+    assert_eq!(classify(0xffff_8000_0000_1000), AddressClass::KernelSpace);
+    assert_eq!(classify(0x_1234_5000), AddressClass::Other);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,214 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{self, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::sync::IrqSafeMutex;
+use crate::workqueue::{self, Work};
+use crate::{cpu, cpu_local, thread};
+
+// Read-copy-update: readers access shared data without taking a lock, and
+// writers publish a new version and free the old one once every reader that
+// could still see it is done.
+//
+// A reader enters a read-side critical section with `read_lock`. Threads
+// are not preempted inside one, and must not block in it, so the sections
+// on one CPU are always nested. A CPU outside of any section is in a
+// quiescent state. A grace period has passed once every CPU was seen in a
+// quiescent state after it started, which `synchronize_rcu` waits for.
+
+cpu_local! {
+    /// Nesting depth of the read-side critical sections of each CPU.
+    static READ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+cpu_local! {
+    /// Number of read-side critical sections each CPU left, counting only
+    /// the outermost ones. A CPU whose count changed passed through a
+    /// quiescent state in between.
+    static QUIESCENT_STATES: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Callbacks queued by `call_rcu` that wait for the next grace period.
+static CALLBACKS: IrqSafeMutex<Vec<Box<dyn FnOnce() + Send>>> = IrqSafeMutex::new(Vec::new());
+static RECLAIM: Work = Work::new(reclaim);
+
+static GRACE_PERIODS: AtomicU64 = AtomicU64::new(0);
+
+/// Marks a read-side critical section until it is dropped. Data read
+/// through `RcuCell::read` stays valid for as long as the guard lives.
+///
+/// The guard must be dropped on the CPU that created it, so it is neither
+/// `Send` nor `Sync`.
+pub struct RcuReadGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enters a read-side critical section. This is a single increment of a
+/// per-CPU counter, and may be done in any context, including interrupt
+/// handlers. The section must not block.
+pub fn read_lock() -> RcuReadGuard {
+    // the locked increment is a full barrier, so no read of the section can
+    // happen before it
+    READ_DEPTH.get().fetch_add(1, Ordering::SeqCst);
+    RcuReadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        if READ_DEPTH.get().fetch_sub(1, Ordering::SeqCst) == 1 {
+            QUIESCENT_STATES.get().fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// Returns `true` if the current CPU is inside a read-side critical
+/// section.
+pub fn in_read_section() -> bool {
+    READ_DEPTH.get().load(Ordering::Relaxed) != 0
+}
+
+/// Blocks until every read-side critical section that was active when it
+/// was called has ended. Sections started later may still be running.
+///
+/// Must not be called from interrupt handlers or inside a read-side
+/// critical section.
+pub fn synchronize_rcu() {
+    assert!(
+        !in_read_section(),
+        "synchronize_rcu inside an RCU read-side critical section"
+    );
+    // order the caller's updates before the sampling below
+    atomic::fence(Ordering::SeqCst);
+
+    let mut seen = [0; cpu::MAX_CPUS];
+    for (cpu, seen) in seen.iter_mut().enumerate() {
+        *seen = QUIESCENT_STATES.get_for(cpu).load(Ordering::Acquire);
+    }
+    for (cpu, &seen) in seen.iter().enumerate() {
+        loop {
+            let idle = READ_DEPTH.get_for(cpu).load(Ordering::SeqCst) == 0;
+            let passed = QUIESCENT_STATES.get_for(cpu).load(Ordering::Acquire) != seen;
+            if idle || passed {
+                break;
+            }
+            // returns right away before the scheduler is initialised
+            thread::yield_now();
+        }
+    }
+
+    atomic::fence(Ordering::SeqCst);
+    GRACE_PERIODS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Runs `callback` in process context once a grace period passed. It may
+/// be called inside read-side critical sections, but not from interrupt
+/// handlers, as it allocates.
+///
+/// Callbacks run on the system work queue, so `workqueue::init` must have
+/// been called.
+pub fn call_rcu<F>(callback: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    CALLBACKS.lock().push(Box::new(callback));
+    workqueue::system().queue(&RECLAIM);
+}
+
+/// Blocks until all callbacks queued with `call_rcu` before ran.
+pub fn barrier() {
+    workqueue::system().flush();
+}
+
+/// Returns the number of grace periods that ended so far.
+pub fn grace_periods() -> u64 {
+    GRACE_PERIODS.load(Ordering::Relaxed)
+}
+
+fn reclaim() {
+    let callbacks = core::mem::take(&mut *CALLBACKS.lock());
+    if callbacks.is_empty() {
+        return;
+    }
+    synchronize_rcu();
+    for callback in callbacks {
+        callback();
+    }
+}
+
+/// A pointer to a heap allocated value that is read under RCU. Readers get
+/// a reference without taking a lock; writers replace the value as a
+/// whole, and the old one is dropped after a grace period.
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> RcuCell<T> {
+        RcuCell {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+        }
+    }
+
+    /// Returns the current value. It stays valid until the read-side
+    /// critical section of `guard` ends, even if it is replaced meanwhile.
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Publishes `value` and drops the previous value with `call_rcu`.
+    pub fn update(&self, value: T) {
+        let old = self.swap(value);
+        call_rcu(move || drop(old));
+    }
+
+    /// Publishes `value`, waits for a grace period and returns the previous
+    /// value.
+    ///
+    /// Must not be called from interrupt handlers or inside a read-side
+    /// critical section.
+    pub fn replace(&self, value: T) -> T {
+        let old = self.swap(value);
+        synchronize_rcu();
+        *old.into_box()
+    }
+
+    fn swap(&self, value: T) -> Retired<T> {
+        let new = Box::into_raw(Box::new(value));
+        Retired(self.ptr.swap(new, Ordering::AcqRel))
+    }
+}
+
+// Readers on other CPUs get `&T`, and the value is dropped wherever the
+// last writer runs.
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // no reader can hold a reference into a cell that is dropped
+        unsafe { drop(Box::from_raw(*self.ptr.get_mut())) };
+    }
+}
+
+/// A value that was replaced, but may still be read.
+struct Retired<T>(*mut T);
+
+// Only moved to the thread that frees it, once no reader can access it.
+unsafe impl<T: Send> Send for Retired<T> {}
+
+impl<T> Retired<T> {
+    fn into_box(self) -> Box<T> {
+        let ptr = self.0;
+        core::mem::forget(self);
+        unsafe { Box::from_raw(ptr) }
+    }
+}
+
+impl<T> Drop for Retired<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.0)) };
+    }
+}
@@ -0,0 +1,357 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{self as irq, InterruptIndex, IrqReturn};
+use crate::sync::SpinLock;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const REG_SECONDS: u8 = 0x00;
+const REG_ALARM_SECONDS: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_ALARM_MINUTES: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_ALARM_HOURS: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_B_ALARM: u8 = 1 << 5;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const HOUR_PM: u8 = 1 << 7;
+
+/// The CMOS RTC only stores two digits of the year; years below this pivot
+/// are placed in the 21st century.
+const CENTURY_PIVOT: u16 = 70;
+
+/// The CMOS index and data ports. Every register access consists of an
+/// index write followed by a data access, so both must happen under the
+/// same lock.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> [u8; 6] {
+        while self.update_in_progress() {}
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+        ]
+    }
+}
+
+static CMOS: SpinLock<Cmos> = SpinLock::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+static PERIODIC_HANDLER: SpinLock<Option<fn()>> = SpinLock::new(None);
+static ALARM_HANDLER: SpinLock<Option<fn()>> = SpinLock::new(None);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static ALARMS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the RTC interrupt handler on IRQ 8. The clock raises no
+/// interrupts until the periodic interrupt or the alarm is enabled.
+pub fn init() {
+    irq::register_irq(InterruptIndex::Rtc.line(), handle_interrupt)
+        .expect("failed to register RTC handler");
+}
+
+/// A wall-clock date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), u32::from(self.month), u32::from(self.day));
+        let seconds = u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        days as u64 * 86400 + seconds
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Reads the current date and time from the CMOS clock.
+///
+/// The registers are read until two consecutive reads agree, so that an
+/// update of the clock in the middle of a read is never observed.
+pub fn read() -> DateTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(REG_STATUS_B))
+    });
+
+    let [second, minute, hour, day, month, year] = raw;
+    let decode = |value: u8| from_register(value, status_b);
+    let year = u16::from(decode(year));
+
+    DateTime {
+        year: if year < CENTURY_PIVOT { 2000 + year } else { 1900 + year },
+        month: decode(month),
+        day: decode(day),
+        hour: decode_hour(hour, status_b),
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Returns the current Unix timestamp according to the CMOS clock.
+pub fn unix_timestamp() -> u64 {
+    read().to_unix_timestamp()
+}
+
+/// Sets the CMOS clock to the given date and time.
+///
+/// The value is stored in whichever format (BCD or binary, 12 or 24 hour)
+/// the clock is currently configured for.
+pub fn set(datetime: &DateTime) {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        let encode = |value: u8| to_register(value, status_b);
+
+        // halt updates while the registers are being written
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_SET);
+        cmos.write(REG_SECONDS, encode(datetime.second));
+        cmos.write(REG_MINUTES, encode(datetime.minute));
+        cmos.write(REG_HOURS, encode_hour(datetime.hour, status_b));
+        cmos.write(REG_DAY, encode(datetime.day));
+        cmos.write(REG_MONTH, encode(datetime.month));
+        cmos.write(REG_YEAR, encode((datetime.year % 100) as u8));
+        cmos.write(REG_STATUS_B, status_b & !STATUS_B_SET);
+    });
+}
+
+/// Enables the periodic interrupt at `32768 >> (rate - 1)` Hz and calls
+/// `handler` from the RTC interrupt handler on every tick.
+///
+/// `rate` must be between 3 (8192 Hz) and 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8, handler: fn()) {
+    assert!(rate >= 3 && rate <= 15, "invalid RTC rate {}", rate);
+
+    interrupts::without_interrupts(|| {
+        *PERIODIC_HANDLER.lock() = Some(handler);
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(REG_STATUS_A);
+        cmos.write(REG_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // discard any interrupt that is already pending
+        cmos.read(REG_STATUS_C);
+    });
+}
+
+/// Disables the periodic interrupt.
+pub fn disable_periodic_interrupt() {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+        *PERIODIC_HANDLER.lock() = None;
+    });
+}
+
+/// Arms the daily alarm for the given time and calls `handler` from the RTC
+/// interrupt handler when it fires.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: fn()) {
+    interrupts::without_interrupts(|| {
+        *ALARM_HANDLER.lock() = Some(handler);
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_ALARM_SECONDS, to_register(second, status_b));
+        cmos.write(REG_ALARM_MINUTES, to_register(minute, status_b));
+        cmos.write(REG_ALARM_HOURS, encode_hour(hour, status_b));
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_ALARM);
+        cmos.read(REG_STATUS_C);
+    });
+}
+
+/// Disarms the alarm.
+pub fn cancel_alarm() {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b & !STATUS_B_ALARM);
+        *ALARM_HANDLER.lock() = None;
+    });
+}
+
+/// Returns the number of periodic interrupts received so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of alarm interrupts received so far.
+pub fn alarms() -> u64 {
+    ALARMS.load(Ordering::Relaxed)
+}
+
+/// Acknowledges an RTC interrupt and dispatches it to the registered
+/// handlers. This is the IRQ 8 handler.
+fn handle_interrupt() -> IrqReturn {
+    // status register C must be read, otherwise no further interrupt is raised
+    let status_c = CMOS.lock().read(REG_STATUS_C);
+
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = *PERIODIC_HANDLER.lock() {
+            handler();
+        }
+    }
+    if status_c & STATUS_C_ALARM != 0 {
+        ALARMS.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = *ALARM_HANDLER.lock() {
+            handler();
+        }
+    }
+
+    if status_c & (STATUS_C_PERIODIC | STATUS_C_ALARM) != 0 {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotHandled
+    }
+}
+
+fn from_register(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+        value
+    } else {
+        (value & 0x0f) + (value >> 4) * 10
+    }
+}
+
+fn to_register(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+        value
+    } else {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+/// Decodes the hour register into 0-23, handling the 12 hour format where
+/// the top bit marks PM and 12 means midnight or noon.
+fn decode_hour(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return from_register(value, status_b);
+    }
+    let hour = from_register(value & !HOUR_PM, status_b) % 12;
+    if value & HOUR_PM != 0 { hour + 12 } else { hour }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return to_register(hour, status_b);
+    }
+    let hour_12 = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    let pm = if hour >= 12 { HOUR_PM } else { 0 };
+    to_register(hour_12, status_b) | pm
+}
+
+/// Returns the number of days since 1970-01-01 for the given civil date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+        + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    serial_print!("test_unix_timestamp... ");
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(epoch.to_unix_timestamp(), 0);
+    let leap_day = DateTime { year: 2020, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    assert_eq!(leap_day.to_unix_timestamp(), 1_582_983_462);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_hour_formats() {
+    serial_print!("test_hour_formats... ");
+    let bcd_12_hour = 0;
+    for hour in 0..24 {
+        let encoded = encode_hour(hour, bcd_12_hour);
+        assert_eq!(decode_hour(encoded, bcd_12_hour), hour);
+    }
+    assert_eq!(encode_hour(0, bcd_12_hour), 0x12);
+    assert_eq!(encode_hour(13, bcd_12_hour), HOUR_PM | 0x01);
+    assert_eq!(decode_hour(0x23, STATUS_B_24_HOUR), 23);
+    assert_eq!(decode_hour(23, STATUS_B_24_HOUR | STATUS_B_BINARY), 23);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read_rtc() {
+    serial_print!("test_read_rtc... ");
+    let now = read();
+    assert!(now.month >= 1 && now.month <= 12);
+    assert!(now.day >= 1 && now.day <= 31);
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,517 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::{self as irq, InterruptIndex, IrqReturn};
+use crate::serial_println;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, Thread, ThreadId, ThreadState};
+use crate::time::Instant;
+use crate::timer;
+
+/// Default length of a time slice.
+pub const DEFAULT_TIME_SLICE: Duration = Duration::from_millis(20);
+
+/// Number of priority levels, each with its own run queue.
+const PRIORITY_LEVELS: usize = 3;
+
+/// The priority of a thread. Ready threads of a higher priority always run
+/// first; threads of the same priority share the CPU round-robin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    fn as_index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+pub(crate) struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// The ready threads, indexed by priority.
+    run_queues: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+    pub(crate) current: ThreadId,
+    /// Runs `hlt` whenever no other thread is ready. It is never queued.
+    idle: ThreadId,
+    /// The thread switched away from, whose stack is freed by the next
+    /// thread if it exited.
+    previous: Option<ThreadId>,
+    /// Threads blocked in `wait_for_interrupt`.
+    irq_waiters: Vec<ThreadId>,
+    /// Ticks the current thread has run since it was last scheduled.
+    slice_used: u64,
+    /// When the current thread was switched to, for CPU time accounting.
+    switched_at: Instant,
+}
+
+/// The outcome of picking the next thread to run.
+enum Pick {
+    /// Keep running the current thread.
+    Current,
+    /// Save the current stack pointer to the first address and switch to
+    /// the second stack pointer.
+    Switch(*mut u64, u64),
+}
+
+static SCHEDULER: IrqSafeMutex<Option<Scheduler>> = IrqSafeMutex::new(None);
+/// Set when a thread should be preempted at the end of the current
+/// interrupt.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+/// Set while any thread waits in `wait_for_interrupt`.
+static HAS_IRQ_WAITERS: AtomicBool = AtomicBool::new(false);
+/// The earliest tick at which a sleeping thread must be woken.
+static NEXT_WAKE_TICK: AtomicU64 = AtomicU64::new(u64::max_value());
+/// Length of a time slice in timer ticks.
+static SLICE_TICKS: AtomicU64 = AtomicU64::new(2);
+
+/// Turns the running flow of control into the first thread, creates the
+/// idle thread and starts preempting threads from the timer interrupt.
+///
+/// Must be called after the heap and `memory::init_frame_allocator` are
+/// set up, and only once.
+pub fn init() {
+    let boot = Box::new(Thread::boot());
+    let idle = Box::new(
+        Thread::new(Box::new(idle_thread), Priority::Low).expect("failed to allocate a kernel stack"),
+    );
+    let (current, idle_id) = (boot.id, idle.id);
+    let mut threads = BTreeMap::new();
+    threads.insert(current, boot);
+    threads.insert(idle_id, idle);
+
+    set_time_slice(DEFAULT_TIME_SLICE);
+    *SCHEDULER.lock() = Some(Scheduler {
+        threads,
+        run_queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        current,
+        idle: idle_id,
+        previous: None,
+        irq_waiters: Vec::new(),
+        slice_used: 0,
+        switched_at: Instant::now(),
+    });
+    irq::register_irq(InterruptIndex::Timer.line(), tick)
+        .expect("failed to register scheduler tick handler");
+}
+
+/// Sets how long a thread may run before it is preempted in favour of
+/// another ready thread of the same priority. The slice is rounded up to
+/// whole timer ticks.
+pub fn set_time_slice(slice: Duration) {
+    SLICE_TICKS.store(timer::duration_to_ticks(slice).max(1), Ordering::Relaxed);
+}
+
+/// Returns the length of a time slice.
+pub fn time_slice() -> Duration {
+    let ticks = SLICE_TICKS.load(Ordering::Relaxed);
+    Duration::from_millis(ticks * 1000 / timer::TICKS_PER_SECOND)
+}
+
+/// Runs `f` with the scheduler locked and interrupts disabled.
+pub(crate) fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let mut scheduler = SCHEDULER.lock();
+    f(scheduler
+        .as_mut()
+        .expect("scheduler not initialised, call scheduler::init first"))
+}
+
+impl Scheduler {
+    pub(crate) fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("unknown thread")
+    }
+
+    fn current_priority(&self) -> Option<Priority> {
+        if self.current == self.idle {
+            None
+        } else {
+            Some(self.threads[&self.current].priority)
+        }
+    }
+
+    fn highest_ready(&self) -> Option<Priority> {
+        [Priority::High, Priority::Normal, Priority::Low]
+            .iter()
+            .cloned()
+            .find(|priority| !self.run_queues[priority.as_index()].is_empty())
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let thread = self.thread_mut(id);
+        thread.state = ThreadState::Ready;
+        let priority = thread.priority;
+        self.run_queues[priority.as_index()].push_back(id);
+    }
+
+    /// Makes a blocked or sleeping thread ready. If it has a higher priority
+    /// than the running thread, that one is preempted at the end of the
+    /// current interrupt. May be called from interrupt handlers.
+    pub(crate) fn wake(&mut self, id: ThreadId) {
+        match self.thread_mut(id).state {
+            ThreadState::Blocked | ThreadState::Sleeping => {}
+            _ => return,
+        }
+        self.enqueue(id);
+        if Some(self.threads[&id].priority) > self.current_priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn block_current(&mut self) {
+        let current = self.current;
+        self.thread_mut(current).state = ThreadState::Blocked;
+    }
+
+    pub(crate) fn sleep_current(&mut self, wake_tick: u64) {
+        let current = self.current;
+        let thread = self.thread_mut(current);
+        thread.state = ThreadState::Sleeping;
+        thread.wake_tick = wake_tick;
+        if wake_tick < NEXT_WAKE_TICK.load(Ordering::Relaxed) {
+            NEXT_WAKE_TICK.store(wake_tick, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set_priority(&mut self, id: ThreadId, priority: Priority) {
+        let thread = self.thread_mut(id);
+        let old = thread.priority;
+        thread.priority = priority;
+        if thread.state == ThreadState::Ready {
+            self.run_queues[old.as_index()].retain(|queued| *queued != id);
+            self.run_queues[priority.as_index()].push_back(id);
+        }
+        if self.highest_ready() > self.current_priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops the record of an exited thread.
+    pub(crate) fn remove(&mut self, id: ThreadId) {
+        debug_assert_eq!(self.thread_mut(id).state, ThreadState::Exited);
+        self.threads.remove(&id);
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        let mut next_wake = u64::max_value();
+        let mut expired = Vec::new();
+        for thread in self.threads.values() {
+            if thread.state != ThreadState::Sleeping {
+                continue;
+            }
+            if thread.wake_tick <= now {
+                expired.push(thread.id);
+            } else {
+                next_wake = next_wake.min(thread.wake_tick);
+            }
+        }
+        NEXT_WAKE_TICK.store(next_wake, Ordering::Relaxed);
+        for id in expired {
+            self.wake(id);
+        }
+    }
+
+    fn wake_irq_waiters(&mut self) {
+        HAS_IRQ_WAITERS.store(false, Ordering::Relaxed);
+        let waiters = core::mem::replace(&mut self.irq_waiters, Vec::new());
+        for id in waiters {
+            self.wake(id);
+        }
+    }
+
+    fn pick_next(&mut self) -> Pick {
+        let current = self.current;
+        let running = self.threads[&current].state == ThreadState::Running;
+        let highest = self.highest_ready();
+
+        if running {
+            // a running thread only gives way to threads of at least its
+            // own priority, and the idle thread to any thread
+            if highest.is_none() || highest < self.current_priority() {
+                self.slice_used = 0;
+                return Pick::Current;
+            }
+            if current == self.idle {
+                self.thread_mut(current).state = ThreadState::Ready;
+            } else {
+                self.enqueue(current);
+            }
+        }
+
+        let next = match self.highest_ready() {
+            Some(priority) => self.run_queues[priority.as_index()].pop_front().unwrap(),
+            None => self.idle,
+        };
+        self.slice_used = 0;
+        self.thread_mut(next).state = ThreadState::Running;
+        if next == current {
+            return Pick::Current;
+        }
+
+        let now = Instant::now();
+        let ran = now.duration_since(self.switched_at);
+        self.switched_at = now;
+        self.thread_mut(current).cpu_time += ran;
+
+        // the threads are boxed, so the saved stack pointer stays in place
+        // when the map changes
+        let old_rsp = &mut self.thread_mut(current).rsp as *mut u64;
+        let new_rsp = self.thread_mut(next).rsp;
+        self.previous = Some(current);
+        self.current = next;
+        Pick::Switch(old_rsp, new_rsp)
+    }
+}
+
+/// Adds a new ready thread, and drops the records of detached threads that
+/// exited.
+pub(crate) fn add(thread: Box<Thread>) {
+    with(|scheduler| {
+        let id = thread.id;
+        scheduler.threads.insert(id, thread);
+        scheduler.enqueue(id);
+        if Some(scheduler.threads[&id].priority) > scheduler.current_priority() {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+
+        let reaped: Vec<ThreadId> = scheduler
+            .threads
+            .values()
+            .filter(|t| t.detached && t.state == ThreadState::Exited && t.stack.is_none())
+            .map(|t| t.id)
+            .collect();
+        for id in reaped {
+            scheduler.threads.remove(&id);
+        }
+    });
+}
+
+/// Switches to the highest priority ready thread. The current thread is
+/// requeued if it is still running, and otherwise stays off the run queues
+/// until it is woken. The idle thread runs if no thread is ready.
+pub(crate) fn schedule() {
+    debug_assert!(
+        !crate::rcu::in_read_section(),
+        "blocking inside an RCU read-side critical section"
+    );
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    let pick = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.pick_next(),
+        None => Pick::Current,
+    };
+    if let Pick::Switch(old_rsp, new_rsp) = pick {
+        // a thread in user mode takes interrupts on its own kernel stack,
+        // set by `usermode::enter`
+        let kernel_stack = crate::cpu::kernel_stack();
+        // spinlocks that leave interrupts enabled stay held while the
+        // thread is switched out
+        #[cfg(feature = "lockdep")]
+        let held_locks = crate::sync::lockdep::take_held();
+        unsafe { thread::switch_context(old_rsp, new_rsp) };
+        #[cfg(feature = "lockdep")]
+        crate::sync::lockdep::restore_held(held_locks);
+        crate::cpu::set_kernel_stack(kernel_stack);
+        finish_switch();
+    }
+
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+}
+
+/// Completes a switch on the stack of the thread switched to, freeing the
+/// stack of the previous thread if it exited.
+pub(crate) fn finish_switch() {
+    let stack = with(|scheduler| {
+        let previous = scheduler.previous.take()?;
+        let thread = scheduler.thread_mut(previous);
+        if thread.state == ThreadState::Exited {
+            thread.stack.take()
+        } else {
+            None
+        }
+    });
+    drop(stack);
+}
+
+/// Blocks the running thread until `wake` is called for it.
+///
+/// To not miss a wake-up from an interrupt handler, the caller must disable
+/// interrupts before checking the condition it waits for. The interrupt
+/// flag is restored when the thread resumes.
+pub fn block_current() {
+    with(Scheduler::block_current);
+    schedule();
+}
+
+/// Makes a blocked thread ready to run again. Does nothing if the thread is
+/// not blocked or sleeping. May be called from interrupt handlers.
+pub fn wake(id: ThreadId) {
+    with(|scheduler| scheduler.wake(id));
+}
+
+/// Waits for the next interrupt. While the scheduler is running, the current
+/// thread blocks so that other threads can run meanwhile; before that, the
+/// CPU halts.
+///
+/// Must be called with interrupts disabled, so that an interrupt arriving
+/// after the caller checked for work is not missed. Returns with interrupts
+/// enabled.
+pub fn wait_for_interrupt() {
+    let blocked = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            scheduler.block_current();
+            let current = scheduler.current;
+            scheduler.irq_waiters.push(current);
+            HAS_IRQ_WAITERS.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    };
+
+    if blocked {
+        schedule();
+        interrupts::enable();
+    } else {
+        crate::enable_interrupts_and_hlt();
+    }
+}
+
+/// Wakes the threads waiting for an interrupt, and switches threads if the
+/// running one used up its time slice or a higher priority thread was
+/// woken. Called at the end of every interrupt, after its EOI.
+///
+/// Threads only run on the bootstrap processor, so interrupts on the other
+/// processors never switch threads.
+pub fn preempt_if_needed() {
+    if crate::cpu::id() != 0 {
+        return;
+    }
+    if HAS_IRQ_WAITERS.load(Ordering::Relaxed) {
+        with(Scheduler::wake_irq_waiters);
+    }
+    // a thread inside an RCU read-side critical section keeps the CPU
+    if crate::rcu::in_read_section() {
+        return;
+    }
+    if NEED_RESCHED.load(Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Shares the timer line to wake sleeping threads and account the time
+/// slice of the running one.
+fn tick() -> IrqReturn {
+    let now = timer::ticks();
+    with(|scheduler| {
+        if now >= NEXT_WAKE_TICK.load(Ordering::Relaxed) {
+            scheduler.wake_sleepers(now);
+        }
+        scheduler.slice_used += 1;
+        let highest = scheduler.highest_ready();
+        let current = scheduler.current_priority();
+        if highest > current
+            || (highest.is_some()
+                && highest == current
+                && scheduler.slice_used >= SLICE_TICKS.load(Ordering::Relaxed))
+        {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    });
+    // the timer handler itself acknowledges the line
+    IrqReturn::NotHandled
+}
+
+/// Halts the CPU until a thread is ready. Interrupt handlers switch away
+/// from it when they wake one.
+fn idle_thread() {
+    loop {
+        crate::enable_interrupts_and_hlt();
+    }
+}
+
+/// A snapshot of a thread, as returned by `threads`.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The time the thread ran for, including the running time slice.
+    pub cpu_time: Duration,
+    /// Whether this is the idle thread.
+    pub idle: bool,
+}
+
+/// Returns a snapshot of every thread.
+pub fn threads() -> Vec<ThreadInfo> {
+    with(|scheduler| {
+        let running_for = scheduler.switched_at.elapsed();
+        scheduler
+            .threads
+            .values()
+            .map(|thread| {
+                let mut cpu_time = thread.cpu_time;
+                if thread.id == scheduler.current {
+                    cpu_time += running_for;
+                }
+                ThreadInfo {
+                    id: thread.id,
+                    state: thread.state,
+                    priority: thread.priority,
+                    cpu_time,
+                    idle: thread.id == scheduler.idle,
+                }
+            })
+            .collect()
+    })
+}
+
+/// Prints every thread with its state, priority and CPU time to the serial
+/// port.
+pub fn dump() {
+    serial_println!("{:>5}  {:<8}  {:<8}  {:>12}", "TID", "PRIORITY", "STATE", "CPU TIME");
+    for thread in threads() {
+        let priority = if thread.idle { "Idle" } else { priority_name(thread.priority) };
+        let micros = thread.cpu_time.as_micros();
+        serial_println!(
+            "{:>5}  {:<8}  {:<8}  {:>5}.{:03} ms",
+            thread.id.as_u64(),
+            priority,
+            state_name(thread.state),
+            micros / 1000,
+            micros % 1000
+        );
+    }
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Low => "Low",
+        Priority::Normal => "Normal",
+        Priority::High => "High",
+    }
+}
+
+fn state_name(state: ThreadState) -> &'static str {
+    match state {
+        ThreadState::Ready => "Ready",
+        ThreadState::Running => "Running",
+        ThreadState::Blocked => "Blocked",
+        ThreadState::Sleeping => "Sleeping",
+        ThreadState::Exited => "Exited",
+    }
+}
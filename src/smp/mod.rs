@@ -0,0 +1,191 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+
+use crate::cpu::PerCpu;
+use crate::thread::stack::KernelStack;
+use crate::time::Instant;
+use crate::{acpi, apic, cpu, gdt, interrupts, memory, println, tlb};
+
+mod trampoline;
+
+/// Time an application processor gets to report online after its startup
+/// IPIs were sent.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Offset of the first entry in the MADT, after the header, the local APIC
+/// address and the flags.
+const MADT_ENTRIES_OFFSET: u64 = 44;
+const MADT_LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// Starts every application processor listed in the ACPI MADT and returns
+/// the number of CPUs online.
+///
+/// Needs the local APIC of the bootstrap processor enabled by `apic::init`
+/// and the frame allocator from `memory::init_frame_allocator`. Only CPUs
+/// with an xAPIC entry are started, and at most `cpu::MAX_CPUS` of them.
+pub fn init() -> usize {
+    let bsp = apic::id();
+    if !apic::is_enabled() || cpu::add(bsp).is_none() {
+        return cpu::count();
+    }
+
+    let others: Vec<u32> = acpi::find_table(b"APIC")
+        .map(|madt| unsafe { local_apic_ids(madt) })
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&id| id != bsp)
+        .collect();
+    if others.is_empty() {
+        return cpu::count();
+    }
+
+    let trampoline = match install_trampoline() {
+        Some(trampoline) => trampoline,
+        None => {
+            println!("no memory for the AP trampoline, running on the BSP only");
+            return cpu::count();
+        }
+    };
+
+    tlb::init();
+    for apic_id in others {
+        let index = match cpu::add(apic_id) {
+            Some(index) => index,
+            None => break,
+        };
+        if !start(trampoline, index, apic_id) {
+            println!("CPU {} (APIC ID {}) did not come online", index, apic_id);
+        }
+    }
+    cpu::count()
+}
+
+/// Returns the local APIC IDs of the enabled processors in the MADT at the
+/// given physical address.
+///
+/// This function is unsafe because the caller must guarantee that `madt`
+/// points to the MADT.
+unsafe fn local_apic_ids(madt: PhysAddr) -> Vec<u32> {
+    let mut ids = Vec::new();
+    let header = match acpi::read_header(madt) {
+        Some(header) => header,
+        None => return ids,
+    };
+    let start = match memory::phys_to_virt(madt) {
+        Some(start) => start.as_ptr::<u8>(),
+        None => return ids,
+    };
+
+    let mut offset = MADT_ENTRIES_OFFSET as usize;
+    while offset + 2 <= header.length as usize {
+        let entry = start.add(offset);
+        let (kind, length) = (*entry, usize::from(*entry.add(1)));
+        if length < 2 {
+            break;
+        }
+        if kind == MADT_LOCAL_APIC && length >= 8 {
+            let flags = core::ptr::read_unaligned(entry.add(4) as *const u32);
+            if flags & LOCAL_APIC_ENABLED != 0 {
+                ids.push(u32::from(*entry.add(3)));
+            }
+        }
+        offset += length;
+    }
+    ids
+}
+
+/// Copies the trampoline to `trampoline::TRAMPOLINE_ADDR` and identity maps
+/// it. Returns the virtual address of the copy.
+///
+/// The page lies in memory the bootloader occupied, which the frame
+/// allocator never hands out. If the memory map says otherwise, the page
+/// may be in use and `None` is returned.
+fn install_trampoline() -> Option<*mut u8> {
+    use bootloader::bootinfo::MemoryRegionType;
+
+    let addr = PhysAddr::new(trampoline::TRAMPOLINE_ADDR);
+    if memory::region_type(addr) != Some(MemoryRegionType::Bootloader) {
+        return None;
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::identity_map(PhysFrame::containing_address(addr), flags).ok()?;
+
+    let dest = memory::phys_to_virt(addr)?.as_mut_ptr::<u8>();
+    unsafe { trampoline::install(dest) };
+    Some(dest)
+}
+
+/// Starts the application processor with the given local APIC ID through
+/// INIT-SIPI-SIPI as CPU `index`. Returns `true` once it reported online.
+fn start(trampoline: *mut u8, index: usize, apic_id: u32) -> bool {
+    let per_cpu = match cpu::new_block(index) {
+        Some(per_cpu) => per_cpu,
+        None => return false,
+    };
+    let stack = match KernelStack::allocate() {
+        Some(stack) => stack,
+        None => return false,
+    };
+    let (level_4_table, _) = Cr3::read();
+    unsafe {
+        trampoline::set_parameters(
+            trampoline,
+            level_4_table.start_address().as_u64(),
+            stack.top().as_u64(),
+            ap_main,
+            per_cpu,
+        );
+    }
+    // the processor keeps running on the stack even if it comes online too
+    // late, so it is never freed
+    core::mem::forget(stack);
+
+    let online = cpu::count();
+    apic::send_init(apic_id);
+    delay(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (trampoline::TRAMPOLINE_ADDR >> 12) as u8);
+        delay(Duration::from_micros(200));
+        if cpu::count() > online {
+            return true;
+        }
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < STARTUP_TIMEOUT {
+        if cpu::count() > online {
+            return true;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    false
+}
+
+fn delay(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Entry point of the application processors, called by the trampoline with
+/// the per-CPU block and on the stack allocated in `start`.
+///
+/// Threads are only scheduled on the bootstrap processor, so the other
+/// processors just handle the interrupts sent to them.
+extern "C" fn ap_main(per_cpu: *mut PerCpu) -> ! {
+    unsafe { cpu::init_ap(per_cpu) };
+    gdt::init_ap();
+    interrupts::init_idt();
+    apic::init();
+    tlb::init_cpu();
+    cpu::set_online();
+    println!("CPU {} online (APIC ID {})", cpu::id(), apic::id());
+
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop();
+}
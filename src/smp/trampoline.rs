@@ -0,0 +1,121 @@
+use core::ptr;
+
+use crate::cpu::PerCpu;
+
+// Application processors start in real mode at the beginning of the page the
+// trampoline is copied to. It switches straight to long mode, using the
+// page tables of the bootstrap processor, and calls the entry point with the
+// per-CPU block and on the stack given in the parameters at its end.
+//
+// The code runs from its copy, so every address in it is computed relative
+// to `ap_trampoline_start` and rebased to `TRAMPOLINE_ADDR`.
+global_asm!(r#"
+.set TRAMPOLINE_ADDR, 0x8000
+.set CR0_PE_PG_WP, 0x80010001
+.set CR4_PAE_PGE, 0xa0
+.set IA32_EFER, 0xc0000080
+.set EFER_LME_NXE, 0x900
+
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_per_cpu
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    mov $CR4_PAE_PGE, %eax
+    mov %eax, %cr4
+    movl ap_trampoline_cr3 - ap_trampoline_start + TRAMPOLINE_ADDR, %eax
+    mov %eax, %cr3
+    mov $IA32_EFER, %ecx
+    rdmsr
+    or $EFER_LME_NXE, %eax
+    wrmsr
+
+    lgdtl ap_trampoline_gdt_pointer - ap_trampoline_start + TRAMPOLINE_ADDR
+    mov %cr0, %eax
+    or $CR0_PE_PG_WP, %eax
+    mov %eax, %cr0
+    ljmpl $0x8, $(ap_trampoline_long_mode - ap_trampoline_start + TRAMPOLINE_ADDR)
+
+.code64
+ap_trampoline_long_mode:
+    mov ap_trampoline_stack - ap_trampoline_start + TRAMPOLINE_ADDR, %rsp
+    mov ap_trampoline_entry - ap_trampoline_start + TRAMPOLINE_ADDR, %rax
+    mov ap_trampoline_per_cpu - ap_trampoline_start + TRAMPOLINE_ADDR, %rdi
+    xor %rbp, %rbp
+    call *%rax
+    ud2
+
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00209a0000000000
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+    .long ap_trampoline_gdt - ap_trampoline_start + TRAMPOLINE_ADDR
+
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_per_cpu:
+    .quad 0
+ap_trampoline_end:
+"#);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_per_cpu: u8;
+}
+
+/// Physical address the trampoline must be copied to. It has to match the
+/// address the code was assembled for.
+pub(super) const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Copies the trampoline to `dest`, the virtual address of
+/// `TRAMPOLINE_ADDR`.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// memory at `dest` is unused.
+pub(super) unsafe fn install(dest: *mut u8) {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    ptr::copy_nonoverlapping(start, dest, len);
+}
+
+/// Sets the page table, stack, entry point and per-CPU block for the next
+/// application processor started from the trampoline copy at `dest`.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// trampoline was installed at `dest` and that no processor is starting up
+/// from it.
+pub(super) unsafe fn set_parameters(
+    dest: *mut u8,
+    cr3: u64,
+    stack_top: u64,
+    entry: extern "C" fn(*mut PerCpu) -> !,
+    per_cpu: *mut PerCpu,
+) {
+    let offset = |field: &u8| field as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+    ptr::write_volatile(dest.add(offset(&ap_trampoline_cr3)) as *mut u64, cr3);
+    ptr::write_volatile(dest.add(offset(&ap_trampoline_stack)) as *mut u64, stack_top);
+    ptr::write_volatile(dest.add(offset(&ap_trampoline_entry)) as *mut u64, entry as u64);
+    ptr::write_volatile(dest.add(offset(&ap_trampoline_per_cpu)) as *mut u64, per_cpu as u64);
+}
@@ -0,0 +1,60 @@
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable, used together with a `Mutex` to wait for the data
+/// it protects to change.
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    #[track_caller]
+    pub const fn new() -> Condvar {
+        Condvar {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex of `guard`, parks the running thread until it is
+    /// notified and reacquires the mutex.
+    ///
+    /// The mutex is only released once the thread is queued, so a
+    /// notification sent after checking the data is not lost.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        core::mem::forget(guard);
+        self.queue.wait_if(|| {
+            mutex.unlock();
+            true
+        });
+        mutex.lock()
+    }
+
+    /// Waits until `condition` returns `false` for the protected data.
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes the longest waiting thread. Returns `false` if no thread was
+    /// waiting.
+    pub fn notify_one(&self) -> bool {
+        self.queue.notify_one()
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        self.queue.notify_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
@@ -0,0 +1,253 @@
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// The guard saves RFLAGS.IF before disabling interrupts and restores it
+/// when dropped, so the lock can be shared between normal code and
+/// interrupt handlers without deadlocking. In debug builds, acquiring the
+/// lock again on the CPU that already holds it panics with the location of
+/// the first acquisition. With the `lockdep` feature, the order in which
+/// locks are acquired is validated, see `sync::lockdep`.
+pub struct IrqSafeMutex<T: ?Sized> {
+    #[cfg(debug_assertions)]
+    owner: Owner,
+    /// Where the lock was created, which determines its lock class.
+    #[cfg(feature = "lockdep")]
+    created: &'static Location<'static>,
+    inner: spin::Mutex<T>,
+}
+
+/// The CPU holding the lock (plus one, so that zero means unlocked) and the
+/// location where it was acquired.
+#[cfg(debug_assertions)]
+struct Owner {
+    cpu: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    mutex: &'a IrqSafeMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> IrqSafeMutex<T> {
+        IrqSafeMutex {
+            #[cfg(debug_assertions)]
+            owner: Owner {
+                cpu: AtomicUsize::new(0),
+                location: AtomicPtr::new(core::ptr::null_mut()),
+            },
+            #[cfg(feature = "lockdep")]
+            created: Location::caller(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Disables interrupts and acquires the lock, spinning until it is
+    /// available.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(debug_assertions)]
+        self.check_recursion();
+        #[cfg(feature = "lockdep")]
+        super::lockdep::acquire(self.class(), Location::caller(), false);
+
+        let guard = self.inner.lock();
+        self.set_owner(Location::caller());
+        IrqSafeMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+            interrupts_enabled,
+        }
+    }
+
+    /// Tries to acquire the lock without spinning. Interrupts are only left
+    /// disabled if the lock was acquired.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lockdep")]
+                super::lockdep::acquired_try(self.class(), Location::caller());
+                self.set_owner(Location::caller());
+                Some(IrqSafeMutexGuard {
+                    mutex: self,
+                    guard: ManuallyDrop::new(guard),
+                    interrupts_enabled,
+                })
+            }
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        match self.try_lock() {
+            Some(_) => false,
+            None => true,
+        }
+    }
+
+    /// Returns the location where the lock was acquired while it is held.
+    /// Always `None` in release builds.
+    pub fn owner_location(&self) -> Option<&'static Location<'static>> {
+        #[cfg(debug_assertions)]
+        {
+            let location = self.owner.location.load(Ordering::Relaxed);
+            if self.owner.cpu.load(Ordering::Relaxed) != 0 && !location.is_null() {
+                return Some(unsafe { &*location });
+            }
+        }
+        None
+    }
+
+    /// Releases the lock regardless of who holds it.
+    ///
+    /// This function is unsafe because the holder may still be using the
+    /// protected value. It is only meant for fatal paths, such as printing
+    /// a panic message after the holder can no longer run.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.class());
+        self.clear_owner();
+        self.inner.force_unlock();
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn class(&self) -> super::lockdep::LockClass {
+        super::lockdep::LockClass {
+            name: core::any::type_name::<T>(),
+            created: self.created,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_recursion(&self) {
+        if self.owner.cpu.load(Ordering::Relaxed) == crate::cpu::id() + 1 {
+            let location = self.owner_location();
+            // the holder never resumes, so release the lock for the panic
+            // handler, which may need it to print
+            unsafe { self.force_unlock() };
+            match location {
+                Some(location) => panic!("recursive lock acquisition, already locked at {}", location),
+                None => panic!("recursive lock acquisition"),
+            }
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn set_owner(&self, location: &'static Location<'static>) {
+        #[cfg(debug_assertions)]
+        {
+            self.owner.location.store(location as *const _ as *mut _, Ordering::Relaxed);
+            self.owner.cpu.store(crate::cpu::id() + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn clear_owner(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.cpu.store(0, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSafeMutex<T> {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "IrqSafeMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "IrqSafeMutex {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.clear_owner();
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.mutex.class());
+        // release the lock before interrupts can arrive again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_irq_safe_mutex_restores_interrupts() {
+    serial_print!("test_irq_safe_mutex_restores_interrupts... ");
+    let mutex = IrqSafeMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!mutex.is_locked());
+
+    interrupts::without_interrupts(|| {
+        drop(mutex.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*mutex.lock(), 1);
+    serial_println!("[ok]");
+}
+
+#[cfg(debug_assertions)]
+#[test_case]
+fn test_irq_safe_mutex_owner_location() {
+    serial_print!("test_irq_safe_mutex_owner_location... ");
+    let mutex = IrqSafeMutex::new(());
+    assert!(mutex.owner_location().is_none());
+    let guard = mutex.lock();
+    assert_eq!(mutex.owner_location().map(|l| l.file()), Some(file!()));
+    drop(guard);
+    assert!(mutex.owner_location().is_none());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,482 @@
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+
+use crate::cpu_local;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Maximum number of lock classes. The validator turns itself off once more
+/// classes are seen.
+pub const MAX_CLASSES: usize = 64;
+
+/// Maximum number of locks a thread can hold at once while being validated.
+pub const MAX_HELD: usize = 16;
+
+type Site = &'static Location<'static>;
+
+/// Identifies the class of a lock: the type it protects and where it was
+/// created. Locks created at the same place, such as the locks of all
+/// `WaitQueue`s created by one function, share their class.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LockClass {
+    pub(crate) name: &'static str,
+    pub(crate) created: Site,
+}
+
+impl fmt::Display for LockClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (created at {})", self.name, self.created)
+    }
+}
+
+/// A lock class as seen by the validator.
+#[derive(Clone, Copy)]
+struct Class {
+    key: LockClass,
+    /// Where a lock of the class was first taken in interrupt context.
+    in_irq: Option<Site>,
+    /// Where a lock of the class was first taken outside interrupt context
+    /// with interrupts enabled.
+    irqs_enabled: Option<Site>,
+}
+
+/// The classes seen so far and the order in which they were acquired.
+struct Graph {
+    classes: [Option<Class>; MAX_CLASSES],
+    count: usize,
+    /// Bit `b` of `after[a]` is set once a lock of class `b` was acquired
+    /// while one of class `a` was held.
+    after: [u64; MAX_CLASSES],
+    /// Where the locks of the first acquisition that added each edge were
+    /// taken.
+    sites: [[Option<(Site, Site)>; MAX_CLASSES]; MAX_CLASSES],
+}
+
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+    classes: [None; MAX_CLASSES],
+    count: 0,
+    after: [0; MAX_CLASSES],
+    sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+});
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The classes of the locks held by a thread or an interrupt handler, in
+/// acquisition order.
+#[derive(Clone, Copy)]
+pub(crate) struct HeldLocks {
+    locks: [(usize, Option<Site>); MAX_HELD],
+    depth: usize,
+}
+
+impl HeldLocks {
+    const EMPTY: HeldLocks = HeldLocks {
+        locks: [(0, None); MAX_HELD],
+        depth: 0,
+    };
+}
+
+cpu_local! {
+    /// The locks held on each CPU. The scheduler swaps them out with the
+    /// running thread, see `take_held`, as locks that leave interrupts
+    /// enabled may be held across preemption.
+    static HELD: Cell<HeldLocks> = Cell::new(HeldLocks::EMPTY);
+}
+
+/// Takes the locks held by the thread the scheduler switches away from.
+/// The thread switched to starts with none, and the switched-out thread
+/// hands the result to `restore_held` once it runs again.
+pub(crate) fn take_held() -> HeldLocks {
+    HELD.with(|held| held.replace(HeldLocks::EMPTY))
+}
+
+/// Makes `locks`, saved by `take_held`, the locks held on this CPU again.
+pub(crate) fn restore_held(locks: HeldLocks) {
+    HELD.with(|held| held.set(locks));
+}
+
+/// Records that a lock of `class` is about to be acquired at `site`, and
+/// reports the acquisition if it can deadlock.
+///
+/// Must be called before spinning on the lock, so that the report is out
+/// before the deadlock happens. `irqs_enabled` tells whether interrupts
+/// stay enabled while the lock is held.
+pub(crate) fn acquire(class: LockClass, site: Site, irqs_enabled: bool) {
+    record(class, site, Some(irqs_enabled));
+}
+
+/// Records a lock acquired by a successful `try_lock`. Such an
+/// acquisition cannot deadlock, so it is not checked, but locks taken
+/// while it is held are.
+pub(crate) fn acquired_try(class: LockClass, site: Site) {
+    record(class, site, None);
+}
+
+/// Records that the most recently acquired lock of `class` was released.
+/// Locks released on another CPU than the one they were acquired on are
+/// ignored.
+pub(crate) fn release(class: LockClass) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let index = match GRAPH.lock().find(class) {
+            Some(index) => index,
+            None => return,
+        };
+        HELD.with(|held| {
+            let mut locks = held.get();
+            let position = locks.locks[..locks.depth]
+                .iter()
+                .rposition(|&(held, _)| held == index);
+            if let Some(position) = position {
+                locks.locks.copy_within(position + 1..locks.depth, position);
+                locks.depth -= 1;
+                held.set(locks);
+            }
+        });
+    });
+}
+
+/// Returns the number of potential deadlocks reported so far.
+pub fn reports() -> usize {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+/// Returns `false` once the validator turned itself off.
+pub fn is_enabled() -> bool {
+    !DISABLED.load(Ordering::Relaxed)
+}
+
+fn record(class: LockClass, site: Site, irqs_enabled: Option<bool>) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let in_irq = crate::interrupts::in_interrupt();
+    interrupts::without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        let index = match graph.find_or_add(class) {
+            Some(index) => index,
+            None => {
+                disable("too many lock classes");
+                return;
+            }
+        };
+
+        HELD.with(|held| {
+            let mut locks = held.get();
+            if let Some(irqs_enabled) = irqs_enabled {
+                graph.check_irq_usage(index, site, in_irq, irqs_enabled);
+                for &(held, held_site) in &locks.locks[..locks.depth] {
+                    graph.check_order(held, held_site, index, site);
+                }
+            }
+            if locks.depth == MAX_HELD {
+                disable("too many locks held");
+                return;
+            }
+            locks.locks[locks.depth] = (index, Some(site));
+            locks.depth += 1;
+            held.set(locks);
+        });
+    });
+}
+
+impl Graph {
+    fn find(&self, key: LockClass) -> Option<usize> {
+        self.classes[..self.count]
+            .iter()
+            .position(|class| class.map(|class| class.key) == Some(key))
+    }
+
+    fn find_or_add(&mut self, key: LockClass) -> Option<usize> {
+        if let Some(index) = self.find(key) {
+            return Some(index);
+        }
+        if self.count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.count] = Some(Class {
+            key,
+            in_irq: None,
+            irqs_enabled: None,
+        });
+        self.count += 1;
+        Some(self.count - 1)
+    }
+
+    fn class(&mut self, index: usize) -> &mut Class {
+        self.classes[index].as_mut().expect("unknown lock class")
+    }
+
+    /// Reports a class used in interrupt context and with interrupts
+    /// enabled, as an interrupt arriving while the lock is held would spin
+    /// on it forever.
+    fn check_irq_usage(&mut self, index: usize, site: Site, in_irq: bool, irqs_enabled: bool) {
+        let class = self.class(index);
+        let first_use = class.in_irq.is_none() || class.irqs_enabled.is_none();
+        if in_irq {
+            class.in_irq = class.in_irq.or(Some(site));
+        } else if irqs_enabled {
+            class.irqs_enabled = class.irqs_enabled.or(Some(site));
+        }
+        if let (true, Some(in_irq), Some(irqs_enabled)) =
+            (first_use, class.in_irq, class.irqs_enabled)
+        {
+            let name = class.key;
+            report(|out| {
+                writeln!(out, "lockdep: {} is taken in interrupt context", name)?;
+                writeln!(out, "  at {}", in_irq)?;
+                writeln!(out, "and with interrupts enabled")?;
+                writeln!(out, "  at {}", irqs_enabled)
+            });
+        }
+    }
+
+    /// Adds the edge from `held` to `acquired`, reporting it first if
+    /// `held` was ever acquired after `acquired`. Locks of the same class
+    /// may be nested.
+    fn check_order(&mut self, held: usize, held_site: Option<Site>, acquired: usize, site: Site) {
+        if held == acquired || self.after[held] & (1 << acquired) != 0 {
+            return;
+        }
+        if let Some(path) = self.path(acquired, held) {
+            let graph = &*self;
+            report(|out| {
+                writeln!(
+                    out,
+                    "lockdep: possible deadlock acquiring {}",
+                    graph.name(acquired)
+                )?;
+                writeln!(out, "  at {}", site)?;
+                writeln!(out, "while holding {}", graph.name(held))?;
+                if let Some(held_site) = held_site {
+                    writeln!(out, "  acquired at {}", held_site)?;
+                }
+                writeln!(out, "which was acquired the other way round before:")?;
+                for edge in path.windows(2) {
+                    let (from, to) = (edge[0], edge[1]);
+                    writeln!(out, "  {} -> {}", graph.name(from), graph.name(to))?;
+                    if let Some((from_site, to_site)) = graph.sites[from][to] {
+                        writeln!(out, "    holding {}", from_site)?;
+                        writeln!(out, "    acquired {}", to_site)?;
+                    }
+                }
+                Ok(())
+            });
+        }
+        // the edge is added either way, so that it is only reported once
+        self.after[held] |= 1 << acquired;
+        if let Some(held_site) = held_site {
+            self.sites[held][acquired] = Some((held_site, site));
+        }
+    }
+
+    /// Returns the classes on a path of edges from `from` to `to`, if there
+    /// is one.
+    fn path(&self, from: usize, to: usize) -> Option<PathBuffer> {
+        // breadth first, remembering where each class was reached from
+        let mut reached_from = [usize::max_value(); MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        reached_from[from] = from;
+        while head < tail {
+            let current = queue[head];
+            head += 1;
+            if current == to {
+                let mut path = PathBuffer::default();
+                let mut class = to;
+                while class != from {
+                    path.push(class);
+                    class = reached_from[class];
+                }
+                path.push(from);
+                path.reverse();
+                return Some(path);
+            }
+            for next in 0..self.count {
+                if self.after[current] & (1 << next) != 0
+                    && reached_from[next] == usize::max_value()
+                {
+                    reached_from[next] = current;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn name(&self, index: usize) -> ClassName {
+        ClassName(self.classes[index].map(|class| class.key))
+    }
+}
+
+/// Prints a class in reports.
+struct ClassName(Option<LockClass>);
+
+impl fmt::Display for ClassName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(class) => class.fmt(f),
+            None => f.write_str("?"),
+        }
+    }
+}
+
+/// The classes of a dependency path, without the heap.
+struct PathBuffer {
+    classes: [usize; MAX_CLASSES],
+    len: usize,
+}
+
+impl Default for PathBuffer {
+    fn default() -> Self {
+        PathBuffer {
+            classes: [0; MAX_CLASSES],
+            len: 0,
+        }
+    }
+}
+
+impl PathBuffer {
+    fn push(&mut self, class: usize) {
+        self.classes[self.len] = class;
+        self.len += 1;
+    }
+
+    fn reverse(&mut self) {
+        self.classes[..self.len].reverse();
+    }
+}
+
+impl core::ops::Deref for PathBuffer {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.classes[..self.len]
+    }
+}
+
+/// Prints a report to the serial port. `SERIAL1` is bypassed, as its lock
+/// may be held by this CPU or be part of the report.
+fn report(print: impl FnOnce(&mut SerialPort) -> fmt::Result) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    let mut serial = unsafe { SerialPort::new(0x3F8) };
+    let _ = print(&mut serial);
+}
+
+fn disable(reason: &str) {
+    if !DISABLED.swap(true, Ordering::Relaxed) {
+        let mut serial = unsafe { SerialPort::new(0x3F8) };
+        let _ = writeln!(serial, "lockdep: {}, turning the validator off", reason);
+    }
+}
+
+#[test_case]
+fn test_lockdep_reports_inversion() {
+    use crate::sync::IrqSafeMutex;
+
+    struct First;
+    struct Second;
+
+    serial_print!("test_lockdep_reports_inversion... ");
+    let first = IrqSafeMutex::new(First);
+    let second = IrqSafeMutex::new(Second);
+    let reports = reports();
+    {
+        let _first = first.lock();
+        let _second = second.lock();
+    }
+    assert_eq!(self::reports(), reports);
+    {
+        let _second = second.lock();
+        let _first = first.lock();
+    }
+    assert_eq!(self::reports(), reports + 1);
+    // the same inversion is only reported once
+    {
+        let _second = second.lock();
+        let _first = first.lock();
+    }
+    assert_eq!(self::reports(), reports + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_lockdep_classes_per_lock() {
+    use crate::sync::IrqSafeMutex;
+
+    serial_print!("test_lockdep_classes_per_lock... ");
+    // the same type, but created at different places
+    let first = IrqSafeMutex::new(0u8);
+    let second = IrqSafeMutex::new(0u8);
+    let reports = reports();
+    {
+        let _first = first.lock();
+        let _second = second.lock();
+    }
+    {
+        let _second = second.lock();
+        let _first = first.lock();
+    }
+    assert_eq!(self::reports(), reports + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_lockdep_held_locks_follow_threads() {
+    use crate::sync::SpinLock;
+
+    struct First;
+    struct Second;
+
+    serial_print!("test_lockdep_held_locks_follow_threads... ");
+    let first = SpinLock::new(First);
+    let second = SpinLock::new(Second);
+    let reports = reports();
+    interrupts::without_interrupts(|| {
+        drop((first.lock(), second.lock()));
+        // a thread holding `second` is switched out, and the next thread
+        // takes `first`, as the scheduler does
+        let second = second.lock();
+        let held = take_held();
+        drop(first.lock());
+        restore_held(held);
+        drop(second);
+    });
+    assert_eq!(self::reports(), reports);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_lockdep_reports_irq_usage() {
+    use crate::interrupts::{register_irq, IrqLine, IrqReturn};
+    use crate::sync::SpinLock;
+
+    struct Shared;
+    static LOCK: SpinLock<Shared> = SpinLock::new(Shared);
+
+    fn take_lock() -> IrqReturn {
+        drop(LOCK.lock());
+        IrqReturn::Handled
+    }
+
+    serial_print!("test_lockdep_reports_irq_usage... ");
+    let reports = reports();
+    let handle = register_irq(IrqLine::Apic(6), take_lock).expect("line in use");
+    unsafe { asm!("int $$0x36" :::: "volatile") };
+    interrupts::without_interrupts(|| drop(LOCK.lock()));
+    assert_eq!(self::reports(), reports);
+    drop(LOCK.lock());
+    assert_eq!(self::reports(), reports + 1);
+    handle.unregister();
+    serial_println!("[ok]");
+}
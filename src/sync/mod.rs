@@ -0,0 +1,28 @@
+// `IrqSafeMutex` spins and may be used anywhere, including interrupt
+// handlers. `SpinLock` spins without disabling interrupts, so a lock shared
+// with interrupt handlers must only be taken with interrupts disabled. The
+// other primitives park the waiting thread on a `WaitQueue` instead, so they
+// need the scheduler and must not be waited on from interrupt handlers;
+// releasing or notifying them from one is fine.
+//
+// With the `lockdep` feature, the two spinlocks report acquisitions in an
+// order that can deadlock, and interrupt-shared locks taken with interrupts
+// enabled.
+
+mod condvar;
+mod irq_safe;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spin_lock;
+mod wait_queue;
+
+pub use self::condvar::Condvar;
+pub use self::irq_safe::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::spin_lock::{SpinLock, SpinLockGuard};
+pub use self::wait_queue::WaitQueue;
@@ -0,0 +1,163 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// Set in `state` while a writer holds the lock; the other bits count the
+/// readers.
+const WRITER: usize = 1 << (usize::max_value().count_ones() - 1);
+
+/// The tags of the threads in the wait queue.
+const READ_WAITER: usize = 0;
+const WRITE_WAITER: usize = 1;
+
+/// A reader-writer lock that parks contending threads instead of spinning.
+///
+/// Threads queue up while any thread is waiting, and releasing the lock
+/// hands it directly to the writer or to the run of readers at the front
+/// of the queue. The lock is thus taken in the order in which threads
+/// started waiting, and a steady stream of readers cannot starve writers.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    /// The number of threads in the queue, only changed with it locked.
+    waiting: AtomicUsize,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Acquires shared access, parking the running thread while a writer
+    /// holds the lock or any thread waits for it.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        // the state only changes with the queue locked, so the checks and
+        // updates in the conditions need no compare-and-swap. A thread that
+        // waited owns the lock once it is woken.
+        self.queue.wait_tagged_if(READ_WAITER, || {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER != 0 || self.waiting.load(Ordering::Relaxed) != 0 {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+            self.state.store(state + 1, Ordering::Acquire);
+            false
+        });
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Acquires exclusive access, parking the running thread while the lock
+    /// is held.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.queue.wait_tagged_if(WRITE_WAITER, || {
+            if self.state.load(Ordering::Relaxed) != 0 {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+            self.state.store(WRITER, Ordering::Acquire);
+            false
+        });
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Returns the number of readers holding the lock.
+    pub fn reader_count(&self) -> usize {
+        self.state.load(Ordering::Relaxed) & !WRITER
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    /// Returns the number of threads waiting for the lock.
+    pub fn waiting_threads(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Updates the state with the queue locked, then hands the lock to the
+    /// writer at the front of the queue once it is free, or to all readers
+    /// at the front once no writer holds it.
+    fn release(&self, update: impl FnOnce(usize) -> usize) {
+        self.queue.notify_front_while(
+            || {
+                let state = self.state.load(Ordering::Relaxed);
+                self.state.store(update(state), Ordering::Release);
+            },
+            |tag| {
+                let state = self.state.load(Ordering::Relaxed);
+                let next = match tag {
+                    WRITE_WAITER if state == 0 => WRITER,
+                    READ_WAITER if state & WRITER == 0 => state + 1,
+                    _ => return false,
+                };
+                self.state.store(next, Ordering::Relaxed);
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+                true
+            },
+        );
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release(|state| state - 1);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release(|state| state & !WRITER);
+    }
+}
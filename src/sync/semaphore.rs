@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A counting semaphore.
+///
+/// A released permit is handed directly to the longest waiting thread, so
+/// threads acquire permits in the order in which they started waiting.
+/// Permits may be released from interrupt handlers.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    #[track_caller]
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit, parking the running thread until one is available.
+    pub fn acquire(&self) {
+        if self.try_acquire() {
+            return;
+        }
+        self.queue.wait_if(|| !self.try_acquire());
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits > 0 {
+            let previous = self.permits.compare_and_swap(permits, permits - 1, Ordering::Acquire);
+            if previous == permits {
+                return true;
+            }
+            permits = previous;
+        }
+        false
+    }
+
+    /// Returns a permit, handing it to the next waiting thread if there is
+    /// one.
+    pub fn release(&self) {
+        self.queue.notify_one_or(|| {
+            self.permits.fetch_add(1, Ordering::Release);
+        });
+    }
+
+    /// Returns the number of permits that can be taken without waiting.
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
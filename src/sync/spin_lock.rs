@@ -0,0 +1,114 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "lockdep")]
+use super::lockdep::{self, LockClass};
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+/// A spinlock that leaves interrupts alone.
+///
+/// It is cheaper than `IrqSafeMutex`, but a lock that is also taken by an
+/// interrupt handler must only be acquired with interrupts disabled, for
+/// example inside `without_interrupts`. With the `lockdep` feature, taking
+/// such a lock with interrupts enabled is reported.
+pub struct SpinLock<T: ?Sized> {
+    /// Where the lock was created, which determines its lock class.
+    #[cfg(feature = "lockdep")]
+    created: &'static Location<'static>,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> SpinLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            #[cfg(feature = "lockdep")]
+            created: Location::caller(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Acquires the lock, spinning until it is available.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(
+            self.class(),
+            Location::caller(),
+            x86_64::instructions::interrupts::are_enabled(),
+        );
+        SpinLockGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class(),
+            guard: self.inner.lock(),
+        }
+    }
+
+    /// Tries to acquire the lock without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquired_try(self.class(), Location::caller());
+        Some(SpinLockGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class(),
+            guard,
+        })
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.try_lock().is_none()
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn class(&self) -> LockClass {
+        LockClass {
+            name: core::any::type_name::<T>(),
+            created: self.created,
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "SpinLock {{ data: {:?} }}", &*guard),
+            None => write!(f, "SpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
@@ -0,0 +1,217 @@
+use core::ptr;
+use x86_64::instructions::interrupts;
+
+use super::IrqSafeMutex;
+use crate::scheduler;
+use crate::thread::{self, ThreadId};
+
+/// A thread waiting on a queue. It lives on the waiting thread's stack for
+/// as long as it is linked into the queue.
+struct Waiter {
+    thread: ThreadId,
+    /// Set by the waiting thread, for example to tell readers from writers.
+    tag: usize,
+    notified: bool,
+    next: *mut Waiter,
+}
+
+/// An intrusive FIFO list of waiters.
+struct Waiters {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+    len: usize,
+}
+
+// The waiters are only accessed with the queue locked.
+unsafe impl Send for Waiters {}
+
+impl Waiters {
+    unsafe fn push_back(&mut self, waiter: *mut Waiter) {
+        (*waiter).next = ptr::null_mut();
+        if self.tail.is_null() {
+            self.head = waiter;
+        } else {
+            (*self.tail).next = waiter;
+        }
+        self.tail = waiter;
+        self.len += 1;
+    }
+
+    unsafe fn pop_front(&mut self) -> Option<*mut Waiter> {
+        if self.head.is_null() {
+            return None;
+        }
+        let waiter = self.head;
+        self.head = (*waiter).next;
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        self.len -= 1;
+        Some(waiter)
+    }
+}
+
+/// A queue of parked threads, woken in the order in which they started
+/// waiting.
+///
+/// Waiting needs no allocation, and notifying may be done from interrupt
+/// handlers.
+pub struct WaitQueue {
+    waiters: IrqSafeMutex<Waiters>,
+}
+
+impl WaitQueue {
+    /// Creates an empty queue. With the `lockdep` feature, the lock of the
+    /// queue is in the class of the caller's location.
+    #[track_caller]
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSafeMutex::new(Waiters {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+                len: 0,
+            }),
+        }
+    }
+
+    /// Calls `should_wait` with the queue locked and, if it returns `true`,
+    /// parks the running thread until it is notified. Returns whether the
+    /// thread waited.
+    ///
+    /// As notifying also locks the queue, a notification cannot slip in
+    /// between the check and parking.
+    pub fn wait_if(&self, should_wait: impl FnOnce() -> bool) -> bool {
+        self.wait_tagged_if(0, should_wait)
+    }
+
+    /// Like `wait_if`, but queues the running thread with `tag`, which
+    /// `notify_front_while` passes back when deciding whether to wake it.
+    pub fn wait_tagged_if(&self, tag: usize, should_wait: impl FnOnce() -> bool) -> bool {
+        let current = thread::current();
+        let mut waiter = Waiter {
+            thread: current,
+            tag,
+            notified: false,
+            next: ptr::null_mut(),
+        };
+        let waiter_ptr: *mut Waiter = &mut waiter;
+
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let mut waiters = self.waiters.lock();
+        let waited = should_wait();
+        if waited {
+            unsafe { waiters.push_back(waiter_ptr) };
+            // a thread may be woken for other reasons, so only leave once
+            // the waiter was taken off the queue
+            loop {
+                scheduler::with(|scheduler| scheduler.block_current());
+                drop(waiters);
+                scheduler::schedule();
+                waiters = self.waiters.lock();
+                if unsafe { (*waiter_ptr).notified } {
+                    break;
+                }
+            }
+        }
+        drop(waiters);
+
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        waited
+    }
+
+    /// Parks the running thread until `condition` returns `true`. The
+    /// condition is checked with the queue locked, before waiting and after
+    /// every notification.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        while self.wait_if(|| !condition()) {}
+    }
+
+    /// Wakes the longest waiting thread. Returns `false` if no thread was
+    /// waiting.
+    pub fn notify_one(&self) -> bool {
+        self.notify_one_or(|| {})
+    }
+
+    /// Wakes the longest waiting thread or, if no thread is waiting, calls
+    /// `otherwise` with the queue still locked. This lets a lock be handed
+    /// over to the next waiter without a thread slipping in between.
+    pub fn notify_one_or(&self, otherwise: impl FnOnce()) -> bool {
+        let mut waiters = self.waiters.lock();
+        match unsafe { waiters.pop_front() } {
+            Some(waiter) => {
+                unsafe { Self::notify(waiter) };
+                true
+            }
+            None => {
+                otherwise();
+                false
+            }
+        }
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        self.notify_all_after(|| {})
+    }
+
+    /// Calls `update` with the queue locked, then wakes all waiting threads
+    /// and returns how many there were. Waiters see the update when they
+    /// recheck their condition.
+    pub fn notify_all_after(&self, update: impl FnOnce()) -> usize {
+        let mut waiters = self.waiters.lock();
+        update();
+        let mut count = 0;
+        while let Some(waiter) = unsafe { waiters.pop_front() } {
+            unsafe { Self::notify(waiter) };
+            count += 1;
+        }
+        count
+    }
+
+    /// Calls `update` with the queue locked, then wakes threads from the
+    /// front of the queue for as long as `hand_over` returns `true` for
+    /// their tag, and returns how many it woke. This lets a lock be handed
+    /// over to a run of waiters at once.
+    pub fn notify_front_while(
+        &self,
+        update: impl FnOnce(),
+        mut hand_over: impl FnMut(usize) -> bool,
+    ) -> usize {
+        let mut waiters = self.waiters.lock();
+        update();
+        let mut count = 0;
+        while !waiters.head.is_null() && hand_over(unsafe { (*waiters.head).tag }) {
+            let waiter = unsafe { waiters.pop_front() }.unwrap();
+            unsafe { Self::notify(waiter) };
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns the number of waiting threads.
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Marks a waiter that was taken off the queue as notified and wakes its
+    /// thread. Must be called with the queue locked, as the waiter may be
+    /// gone once its thread sees the flag.
+    unsafe fn notify(waiter: *mut Waiter) {
+        (*waiter).notified = true;
+        scheduler::wake((*waiter).thread);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> WaitQueue {
+        WaitQueue::new()
+    }
+}
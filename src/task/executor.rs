@@ -0,0 +1,139 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::ArcWake;
+
+use super::{Task, TaskId};
+use crate::{bottom_half, scheduler, watchdog};
+
+/// Maximum number of tasks an executor runs at once. Each task is queued at
+/// most once, so the task queue never overflows.
+const MAX_TASKS: usize = 100;
+
+/// Polls tasks whenever they are woken, and halts the CPU while none is
+/// ready.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// IDs of the woken tasks. Wakers push onto it, possibly from interrupt
+    /// handlers, so it must not take locks.
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    wakers: BTreeMap<TaskId, (Arc<TaskWaker>, Waker)>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            wakers: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a task and queues it to be polled for the first time.
+    ///
+    /// Panics if the executor already runs `MAX_TASKS` tasks.
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        if self.tasks.insert(task_id, task).is_some() {
+            panic!("task with ID {:?} already spawned", task_id);
+        }
+        let task_waker = Arc::new(TaskWaker {
+            task_id,
+            queued: AtomicBool::new(false),
+            task_queue: self.task_queue.clone(),
+        });
+        let waker = futures_util::task::waker(task_waker.clone());
+        task_waker.wake_task();
+        self.wakers.insert(task_id, (task_waker, waker));
+    }
+
+    /// Returns `true` if no spawned task is left.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Polls every task that was woken since the last call, removing the
+    /// ones that completed.
+    pub fn run_ready_tasks(&mut self) {
+        let Executor { tasks, task_queue, wakers } = self;
+
+        while let Ok(task_id) = task_queue.pop() {
+            let (task, (task_waker, waker)) =
+                match (tasks.get_mut(&task_id), wakers.get(&task_id)) {
+                    (Some(task), Some(waker)) => (task, waker),
+                    // a waker outlived its task
+                    _ => continue,
+                };
+            // wake-ups from now on queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let mut context = Context::from_waker(waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                // keep leftover wakers of the task out of the queue
+                task_waker.queued.store(true, Ordering::Release);
+                tasks.remove(&task_id);
+                wakers.remove(&task_id);
+            }
+        }
+    }
+
+    /// Runs the tasks and the bottom halves raised by interrupt handlers
+    /// forever. Takes over the duties of `idle_loop`.
+    pub fn run(&mut self) -> ! {
+        loop {
+            watchdog::touch();
+            bottom_half::run_pending();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Waits for the next interrupt if no task was woken and no bottom half
+    /// is pending. Other threads run meanwhile.
+    ///
+    /// Interrupts are disabled for the check, so a wake-up from an interrupt
+    /// handler cannot slip in between the check and the wait.
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::disable();
+        if self.task_queue.is_empty() && !bottom_half::has_pending() {
+            scheduler::wait_for_interrupt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+/// Wakes a task by queueing its ID on the executor. Waking is lock-free, so
+/// wakers may be used from interrupt handlers.
+struct TaskWaker {
+    task_id: TaskId,
+    /// Set while the task is in the queue, so that waking it again is a
+    /// no-op.
+    queued: AtomicBool,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // cannot fail: each of at most `MAX_TASKS` tasks is queued once
+            let _ = self.task_queue.push(self.task_id);
+        }
+    }
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.wake_task();
+    }
+}
@@ -0,0 +1,101 @@
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyEvent, Keyboard, ScancodeSet1};
+
+use crate::keyboard::{self, Subscription};
+use crate::print;
+
+/// The raw scancodes received from the keyboard since the stream was
+/// created. Every stream receives all of them.
+pub struct ScancodeStream {
+    subscription: Subscription,
+}
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        ScancodeStream {
+            subscription: keyboard::subscribe(),
+        }
+    }
+
+    /// Returns the number of scancodes this stream dropped because it was
+    /// not read fast enough.
+    pub fn dropped(&self) -> u64 {
+        self.subscription.dropped()
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> ScancodeStream {
+        ScancodeStream::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        self.get_mut().subscription.poll_scancode(context).map(Some)
+    }
+}
+
+/// The key presses and releases decoded from a `ScancodeStream`.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl KeyEventStream {
+    pub fn new() -> KeyEventStream {
+        KeyEventStream {
+            scancodes: ScancodeStream::new(),
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode),
+        }
+    }
+
+    /// Translates a key event into a character or raw key, taking the
+    /// state of the modifier keys into account. Returns `None` for events
+    /// that only change that state, such as pressing shift.
+    pub fn decode(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        self.keyboard.process_keyevent(event)
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> KeyEventStream {
+        KeyEventStream::new()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<KeyEvent>> {
+        let this = self.get_mut();
+        loop {
+            let scancode = match Pin::new(&mut this.scancodes).poll_next(context) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            // multi-byte scancodes only produce an event on their last byte
+            if let Ok(Some(event)) = this.keyboard.add_byte(scancode) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+/// Prints the keys typed on the keyboard.
+pub async fn print_keypresses() {
+    let mut events = KeyEventStream::new();
+    while let Some(event) = events.next().await {
+        if let Some(key) = events.decode(event) {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => print!("{:?}", key),
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::scheduler::{self, Priority};
+use crate::timer;
+
+pub mod stack;
+mod switch;
+
+pub(crate) use self::switch::switch_context;
+use self::stack::KernelStack;
+
+/// A unique identifier of a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Recreates an ID from `as_u64`, for example to hand it to an
+    /// interrupt handler through an atomic.
+    pub fn from_u64(id: u64) -> ThreadId {
+        ThreadId(id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in the run queue.
+    Ready,
+    Running,
+    /// Waiting for another thread, for example in `join`.
+    Blocked,
+    /// Waiting for the timer tick in `wake_tick`.
+    Sleeping,
+    /// Finished, but not joined yet.
+    Exited,
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+pub(crate) struct Thread {
+    pub(crate) id: ThreadId,
+    pub(crate) state: ThreadState,
+    /// The saved stack pointer while the thread is not running.
+    pub(crate) rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack, and
+    /// for exited threads once they were switched away from.
+    pub(crate) stack: Option<KernelStack>,
+    /// The function to run, taken when the thread first starts.
+    entry: Option<Entry>,
+    pub(crate) priority: Priority,
+    /// Time spent running, not counting the current run.
+    pub(crate) cpu_time: Duration,
+    pub(crate) wake_tick: u64,
+    /// The thread blocked in `join` on this thread.
+    pub(crate) joiner: Option<ThreadId>,
+    /// Set once the `JoinHandle` was dropped without joining.
+    pub(crate) detached: bool,
+}
+
+impl Thread {
+    /// Creates the record of the thread that initialises the scheduler.
+    pub(crate) fn boot() -> Thread {
+        Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            priority: Priority::Normal,
+            cpu_time: Duration::from_secs(0),
+            wake_tick: 0,
+            joiner: None,
+            detached: true,
+        }
+    }
+
+    pub(crate) fn new(entry: Entry, priority: Priority) -> Option<Thread> {
+        let stack = KernelStack::allocate()?;
+        Some(Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Ready,
+            rsp: switch::initial_stack_pointer(&stack),
+            stack: Some(stack),
+            entry: Some(entry),
+            priority,
+            cpu_time: Duration::from_secs(0),
+            wake_tick: 0,
+            joiner: None,
+            detached: false,
+        })
+    }
+}
+
+/// Starts a new kernel thread running `f` with normal priority.
+///
+/// Panics if no kernel stack could be allocated.
+pub fn spawn<F>(f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_priority(Priority::Normal, f)
+}
+
+/// Starts a new kernel thread running `f` with the given priority.
+///
+/// Panics if no kernel stack could be allocated.
+pub fn spawn_with_priority<F>(priority: Priority, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new(Box::new(f), priority).expect("failed to allocate a kernel stack");
+    let id = thread.id;
+    scheduler::add(Box::new(thread));
+    // run the new thread right away if it has a higher priority
+    scheduler::preempt_if_needed();
+    JoinHandle { id }
+}
+
+/// Returns the ID of the running thread.
+pub fn current() -> ThreadId {
+    scheduler::with(|scheduler| scheduler.current)
+}
+
+/// Changes the priority of the running thread.
+pub fn set_priority(priority: Priority) {
+    scheduler::with(|scheduler| {
+        let current = scheduler.current;
+        scheduler.set_priority(current, priority);
+    });
+    scheduler::preempt_if_needed();
+}
+
+/// Returns the priority of the running thread.
+pub fn priority() -> Priority {
+    scheduler::with(|scheduler| {
+        let current = scheduler.current;
+        scheduler.thread_mut(current).priority
+    })
+}
+
+/// Gives up the CPU to the next ready thread of at least the same
+/// priority, if there is one.
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+/// Blocks the running thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let wake_tick = timer::deadline_after(duration);
+    scheduler::with(|scheduler| scheduler.sleep_current(wake_tick));
+    scheduler::schedule();
+}
+
+/// Terminates the running thread, waking the thread joining it.
+pub fn exit() -> ! {
+    scheduler::with(|scheduler| {
+        let current = scheduler.current;
+        let joiner = scheduler.thread_mut(current).joiner.take();
+        scheduler.thread_mut(current).state = ThreadState::Exited;
+        if let Some(joiner) = joiner {
+            scheduler.wake(joiner);
+        }
+    });
+    scheduler::schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// An owned permission to join a thread. Dropping it detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread has exited.
+    pub fn join(self) {
+        let id = self.id;
+        core::mem::forget(self);
+
+        loop {
+            let exited = scheduler::with(|scheduler| {
+                if scheduler.thread_mut(id).state == ThreadState::Exited {
+                    return true;
+                }
+                let current = scheduler.current;
+                assert!(current != id, "thread tried to join itself");
+                scheduler.thread_mut(id).joiner = Some(current);
+                scheduler.thread_mut(current).state = ThreadState::Blocked;
+                false
+            });
+            if exited {
+                break;
+            }
+            scheduler::schedule();
+        }
+        scheduler::with(|scheduler| scheduler.remove(id));
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let id = self.id;
+        scheduler::with(|scheduler| {
+            if scheduler.thread_mut(id).state == ThreadState::Exited {
+                scheduler.remove(id);
+            } else {
+                scheduler.thread_mut(id).detached = true;
+            }
+        });
+    }
+}
+
+/// Entered through `thread_trampoline` when a new thread is first
+/// switched to.
+#[no_mangle]
+extern "C" fn thread_start() -> ! {
+    scheduler::finish_switch();
+    let entry = scheduler::with(|scheduler| {
+        let current = scheduler.current;
+        scheduler.thread_mut(current).entry.take()
+    });
+    interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
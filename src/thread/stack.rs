@@ -0,0 +1,89 @@
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::memory;
+use crate::sync::IrqSafeMutex;
+
+/// Start of the virtual address range reserved for kernel thread stacks.
+pub const STACKS_START: u64 = 0x_5555_0000_0000;
+/// Maximum number of kernel stacks that can exist at once.
+pub const MAX_STACKS: u64 = 4096;
+/// Number of mapped pages per stack. Each stack is preceded by an unmapped
+/// guard page, so that an overflow faults instead of corrupting memory.
+pub const STACK_PAGES: u64 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+const SLOT_SIZE: u64 = (STACK_PAGES + 1) * PAGE_SIZE;
+const NO_SLOT: u64 = u64::max_value();
+
+struct StackSlots {
+    /// Slots at or above this index were never mapped.
+    next_unused: u64,
+    /// First slot of the free list. Freed stacks stay mapped and store the
+    /// index of the next free slot at their bottom.
+    free: u64,
+}
+
+static SLOTS: IrqSafeMutex<StackSlots> = IrqSafeMutex::new(StackSlots {
+    next_unused: 0,
+    free: NO_SLOT,
+});
+
+/// A kernel stack with a guard page below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: u64,
+}
+
+impl KernelStack {
+    /// Allocates a stack, reusing a freed one if possible. Returns `None`
+    /// if all `MAX_STACKS` are in use or the stack could not be mapped.
+    ///
+    /// Needs the frame allocator from `memory::init_frame_allocator`.
+    pub fn allocate() -> Option<KernelStack> {
+        let mut slots = SLOTS.lock();
+        if slots.free != NO_SLOT {
+            let slot = slots.free;
+            slots.free = unsafe { *(slot_bottom(slot) as *const u64) };
+            return Some(KernelStack { slot });
+        }
+
+        if slots.next_unused == MAX_STACKS {
+            return None;
+        }
+        let slot = slots.next_unused;
+        let start = Page::containing_address(VirtAddr::new(slot_bottom(slot)));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        memory::map_pages(start, STACK_PAGES, flags).ok()?;
+        slots.next_unused += 1;
+        Some(KernelStack { slot })
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(slot_bottom(self.slot))
+    }
+
+    /// Returns the address just above the stack, which is where the stack
+    /// pointer starts.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_PAGES * PAGE_SIZE
+    }
+
+    /// Returns the unmapped page below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom() - PAGE_SIZE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock();
+        unsafe { *(slot_bottom(self.slot) as *mut u64) = slots.free };
+        slots.free = self.slot;
+    }
+}
+
+fn slot_bottom(slot: u64) -> u64 {
+    STACKS_START + slot * SLOT_SIZE + PAGE_SIZE
+}
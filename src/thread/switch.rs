@@ -0,0 +1,57 @@
+use super::stack::KernelStack;
+
+// Only the callee-saved registers need to be preserved: `switch_context` is
+// called like any other function. The interrupt flag is saved and restored
+// by the scheduler around the call.
+global_asm!(r#"
+.global switch_context
+switch_context:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, (%rdi)
+    mov %rsi, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+
+.global thread_trampoline
+thread_trampoline:
+    call thread_start
+    ud2
+"#);
+
+extern "C" {
+    /// Saves the callee-saved registers and the stack pointer of the
+    /// current thread to `old_rsp`, and resumes the thread whose stack
+    /// pointer is `new_rsp`.
+    pub(crate) fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+
+    fn thread_trampoline();
+}
+
+/// Number of callee-saved registers pushed by `switch_context`.
+const SAVED_REGISTERS: usize = 6;
+
+/// Prepares `stack` so that switching to the returned stack pointer enters
+/// `thread_start`.
+pub(super) fn initial_stack_pointer(stack: &KernelStack) -> u64 {
+    // the return address goes just below the 16 byte aligned top, so that
+    // the stack is aligned again when `thread_trampoline` calls into Rust
+    let return_address = (stack.top().as_u64() - 8) as *mut u64;
+    unsafe {
+        return_address.write(thread_trampoline as u64);
+        let registers = return_address.sub(SAVED_REGISTERS);
+        for i in 0..SAVED_REGISTERS {
+            registers.add(i).write(0);
+        }
+        registers as u64
+    }
+}
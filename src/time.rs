@@ -0,0 +1,301 @@
+use crate::timer::{self, TimerHandle};
+use crate::{acpi, memory};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Input frequency of the programmable interval timer.
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const CALIBRATION_MS: u64 = 10;
+const CALIBRATION_RUNS: usize = 3;
+
+static TSC_FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT_TSC: AtomicBool = AtomicBool::new(false);
+
+/// Detects invariant TSC support and calibrates the TSC against the PIT.
+///
+/// Must be called before any `Instant` is converted into a `Duration`.
+pub fn init() {
+    INVARIANT_TSC.store(detect_invariant_tsc(), Ordering::Relaxed);
+    let frequency = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_RUNS).map(|_| calibrate_with_pit()).min().unwrap_or(0)
+    });
+    TSC_FREQUENCY_HZ.store(frequency, Ordering::Relaxed);
+}
+
+/// Re-calibrates the TSC against the HPET main counter if the ACPI tables
+/// describe one. Returns `true` if the HPET was used.
+///
+/// The HPET is only reachable through the physical memory mapping, so this
+/// must be called after `memory::init`.
+pub fn calibrate_with_hpet() -> bool {
+    let frequency = acpi::find_table(b"HPET")
+        .and_then(hpet_base)
+        .and_then(|base| interrupts::without_interrupts(|| unsafe { hpet_calibrate(base) }));
+
+    match frequency {
+        Some(frequency) => {
+            TSC_FREQUENCY_HZ.store(frequency, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the calibrated TSC frequency in Hz, or 0 before `init` was called.
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the CPU reports an invariant TSC, i.e. one that ticks
+/// at a constant rate regardless of power states and frequency changes.
+pub fn has_invariant_tsc() -> bool {
+    INVARIANT_TSC.load(Ordering::Relaxed)
+}
+
+/// A measurement of the time stamp counter, used to measure durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns an instant corresponding to "now".
+    pub fn now() -> Instant {
+        Instant(unsafe { _rdtsc() })
+    }
+
+    /// Returns the amount of time elapsed since this instant was created.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the amount of time elapsed from another instant to this one,
+    /// or zero if that instant is later than this one.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the raw TSC value of this instant.
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Returns a future that completes once `duration` has passed.
+///
+/// The sleeping task is woken from the timer bottom half, so the
+/// resolution is one timer tick.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: timer::deadline_after(duration),
+        timer: None,
+    }
+}
+
+/// Runs `future` until it completes or `duration` has passed, whichever
+/// happens first. The future is dropped on timeout.
+pub fn with_timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// The future returned by `sleep`.
+#[derive(Debug)]
+pub struct Sleep {
+    /// Timer tick at which the sleep ends.
+    deadline: u64,
+    /// The timer waking the task, and the waker it was registered with.
+    timer: Option<(TimerHandle, Waker)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if timer::ticks() >= self.deadline {
+            if let Some((handle, _)) = self.timer.take() {
+                handle.cancel();
+            }
+            return Poll::Ready(());
+        }
+        match self.timer {
+            Some((_, ref waker)) if waker.will_wake(context.waker()) => {}
+            _ => {
+                // the task moved to another waker since the last poll
+                if let Some((handle, _)) = self.timer.take() {
+                    handle.cancel();
+                }
+                let waker = context.waker().clone();
+                let handle = timer::wake_at(self.deadline, waker.clone());
+                self.timer = Some((handle, waker));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((handle, _)) = self.timer.take() {
+            handle.cancel();
+        }
+    }
+}
+
+/// The error returned by `with_timeout` when the duration passed before the
+/// future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// The future returned by `with_timeout`.
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // the wrapped future is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = tsc_frequency();
+    assert!(frequency != 0, "TSC not calibrated, call time::init first");
+
+    let nanos = u128::from(ticks) * NANOS_PER_SEC / u128::from(frequency);
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn detect_invariant_tsc() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Counts TSC ticks during a one-shot countdown of PIT channel 2 and
+/// returns the resulting TSC frequency.
+fn calibrate_with_pit() -> u64 {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    let latch = PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000;
+
+    let (start, end) = unsafe {
+        // enable the channel 2 gate, but keep the speaker disconnected
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel_2.write((latch & 0xff) as u8);
+        channel_2.write((latch >> 8) as u8);
+
+        let start = _rdtsc();
+        // the channel 2 output is reflected in bit 5 of port 0x61
+        while gate.read() & 0x20 == 0 {}
+        (start, _rdtsc())
+    };
+
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Returns the physical base address of the HPET registers from the ACPI
+/// HPET table.
+fn hpet_base(table: x86_64::PhysAddr) -> Option<x86_64::PhysAddr> {
+    // the generic address structure starts after the table header and the
+    // event timer block ID; its 64 bit address field is at offset 4
+    const ADDRESS_OFFSET: u64 = 36 + 4 + 4;
+
+    let virt = memory::phys_to_virt(table + ADDRESS_OFFSET)?;
+    let addr = unsafe { core::ptr::read_unaligned(virt.as_ptr::<u64>()) };
+    if addr == 0 {
+        None
+    } else {
+        Some(x86_64::PhysAddr::new(addr))
+    }
+}
+
+/// Counts TSC ticks while the HPET main counter advances by
+/// `CALIBRATION_MS` and returns the resulting TSC frequency.
+///
+/// This function is unsafe because the caller must guarantee that `base`
+/// is the physical address of the HPET register block.
+unsafe fn hpet_calibrate(base: x86_64::PhysAddr) -> Option<u64> {
+    const CAPABILITIES: u64 = 0x00;
+    const CONFIGURATION: u64 = 0x10;
+    const MAIN_COUNTER: u64 = 0xf0;
+    const FEMTOS_PER_MS: u64 = 1_000_000_000_000;
+
+    let registers = memory::phys_to_virt(base)?;
+    let register = |offset: u64| (registers + offset).as_mut_ptr::<u64>();
+
+    let period_fs = core::ptr::read_volatile(register(CAPABILITIES)) >> 32;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        return None;
+    }
+
+    // make sure the main counter is running
+    let config = core::ptr::read_volatile(register(CONFIGURATION));
+    core::ptr::write_volatile(register(CONFIGURATION), config | 1);
+
+    let hpet_ticks = CALIBRATION_MS * FEMTOS_PER_MS / period_fs;
+    let hpet_start = core::ptr::read_volatile(register(MAIN_COUNTER));
+    let tsc_start = _rdtsc();
+    while core::ptr::read_volatile(register(MAIN_COUNTER)).wrapping_sub(hpet_start) < hpet_ticks {}
+    let tsc_end = _rdtsc();
+    let hpet_end = core::ptr::read_volatile(register(MAIN_COUNTER));
+
+    let elapsed_fs = u128::from(hpet_end.wrapping_sub(hpet_start)) * u128::from(period_fs);
+    let frequency = u128::from(tsc_end - tsc_start) * 1_000_000_000_000_000 / elapsed_fs;
+    Some(frequency as u64)
+}
+
+#[test_case]
+fn test_tsc_calibrated() {
+    serial_print!("test_tsc_calibrated... ");
+    assert!(tsc_frequency() > 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_instant_elapsed() {
+    serial_print!("test_instant_elapsed... ");
+    let start = Instant::now();
+    for _ in 0..1000 {
+        x86_64::instructions::nop();
+    }
+    let later = Instant::now();
+    assert!(later >= start);
+    assert!(later.duration_since(start) <= start.elapsed());
+    assert_eq!(start.duration_since(later), Duration::from_secs(0));
+    serial_println!("[ok]");
+}
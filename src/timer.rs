@@ -0,0 +1,250 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Waker;
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::bottom_half::{self, BottomHalf};
+use crate::interrupts::{self as irq, InterruptIndex, IrqReturn};
+use crate::sync::SpinLock;
+use crate::time::PIT_FREQUENCY_HZ;
+
+/// Frequency of the timer interrupt.
+pub const TICKS_PER_SECOND: u64 = 100;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+/// Handle to a scheduled callback, used to cancel it.
+///
+/// Dropping the handle does not cancel the callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerHandle(u64);
+
+impl TimerHandle {
+    /// Cancels the callback. Returns `false` if it already ran (for one-shot
+    /// timers) or was cancelled before.
+    pub fn cancel(self) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            if timers.entries.remove(&self.0).is_some() {
+                return true;
+            }
+            // a periodic callback that is running right now is not re-armed
+            match timers.running {
+                Some((id, ref mut cancelled)) if id == self.0 && !*cancelled => {
+                    *cancelled = true;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Deadline {
+    tick: u64,
+    id: u64,
+}
+
+struct Timer {
+    callback: Callback,
+    period: Option<u64>,
+}
+
+/// What happens when a deadline expires.
+enum Entry {
+    Callback(Timer),
+    /// Wakes a task sleeping until the deadline.
+    Waker(Waker),
+}
+
+struct TimerQueue {
+    deadlines: BinaryHeap<Reverse<Deadline>>,
+    entries: BTreeMap<u64, Entry>,
+    /// The periodic callback currently being run and whether it was
+    /// cancelled while running.
+    running: Option<(u64, bool)>,
+    next_id: u64,
+}
+
+impl TimerQueue {
+    fn new() -> TimerQueue {
+        TimerQueue {
+            deadlines: BinaryHeap::new(),
+            entries: BTreeMap::new(),
+            running: None,
+            next_id: 0,
+        }
+    }
+
+    fn insert(&mut self, tick: u64, id: u64) {
+        self.deadlines.push(Reverse(Deadline { tick, id }));
+        self.update_next_deadline();
+    }
+
+    /// Publishes the earliest deadline to the interrupt handler.
+    fn update_next_deadline(&self) {
+        let next = self.deadlines.peek().map_or(u64::max_value(), |d| d.0.tick);
+        NEXT_DEADLINE.store(next, Ordering::Release);
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::max_value());
+static BOTTOM_HALF: Once<BottomHalf> = Once::new();
+
+lazy_static! {
+    static ref TIMERS: SpinLock<TimerQueue> = SpinLock::new(TimerQueue::new());
+}
+
+/// Programs PIT channel 0 to raise the timer interrupt `TICKS_PER_SECOND`
+/// times per second and registers the tick handler, which defers expired
+/// callbacks to a bottom half.
+pub fn init() {
+    BOTTOM_HALF.call_once(|| {
+        bottom_half::register(run_expired).expect("no free bottom half")
+    });
+
+    let divisor = PIT_FREQUENCY_HZ / TICKS_PER_SECOND;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+
+    interrupts::without_interrupts(|| unsafe {
+        // channel 0, lobyte/hibyte access, mode 3 (square wave generator)
+        command.write(0b0011_0110);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    });
+
+    irq::register_irq(InterruptIndex::Timer.line(), tick)
+        .expect("failed to register timer handler");
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts a duration into a number of ticks, rounding up so that a
+/// callback never runs early.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() * u128::from(TICKS_PER_SECOND);
+    let ticks = (nanos + 999_999_999) / 1_000_000_000;
+    ticks as u64
+}
+
+/// Returns the first tick by which at least `duration` has passed. The
+/// current tick is already partly over, so a non-zero duration waits for
+/// one more.
+pub fn deadline_after(duration: Duration) -> u64 {
+    match duration_to_ticks(duration) {
+        0 => ticks(),
+        duration_ticks => ticks() + duration_ticks + 1,
+    }
+}
+
+/// Runs `callback` once, after `duration` has passed.
+pub fn after<F>(duration: Duration, callback: F) -> TimerHandle
+where
+    F: FnMut() + Send + 'static,
+{
+    schedule(duration, None, Box::new(callback))
+}
+
+/// Runs `callback` every `duration` until the returned handle is cancelled.
+pub fn every<F>(duration: Duration, callback: F) -> TimerHandle
+where
+    F: FnMut() + Send + 'static,
+{
+    let period = duration_to_ticks(duration).max(1);
+    schedule(duration, Some(period), Box::new(callback))
+}
+
+/// Wakes `waker` from the timer bottom half once `tick` is reached. Used by
+/// the futures in `time`; cancelling the handle drops the waker.
+pub(crate) fn wake_at(tick: u64, waker: Waker) -> TimerHandle {
+    insert_entry(tick, Entry::Waker(waker))
+}
+
+fn schedule(duration: Duration, period: Option<u64>, callback: Callback) -> TimerHandle {
+    insert_entry(deadline_after(duration), Entry::Callback(Timer { callback, period }))
+}
+
+fn insert_entry(deadline: u64, entry: Entry) -> TimerHandle {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.entries.insert(id, entry);
+        timers.insert(deadline, id);
+        TimerHandle(id)
+    })
+}
+
+/// Runs all callbacks and wakes all sleeping tasks whose deadline has
+/// passed. This is the timer bottom half, but it may also be called
+/// directly.
+///
+/// Callbacks run with interrupts enabled and without any timer lock held,
+/// so they may schedule or cancel other callbacks.
+pub fn run_expired() {
+    let now = ticks();
+
+    loop {
+        let expired = interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.deadlines.peek() {
+                Some(Reverse(deadline)) if deadline.tick <= now => {}
+                _ => return None,
+            }
+            let Reverse(deadline) = timers.deadlines.pop().unwrap();
+            timers.update_next_deadline();
+            // a missing entry means the timer was cancelled
+            let entry = timers.entries.remove(&deadline.id);
+            if let Some(Entry::Callback(Timer { period: Some(_), .. })) = entry {
+                timers.running = Some((deadline.id, false));
+            }
+            Some((deadline, entry))
+        });
+
+        let (deadline, mut timer) = match expired {
+            Some((deadline, Some(Entry::Callback(timer)))) => (deadline, timer),
+            Some((_, Some(Entry::Waker(waker)))) => {
+                waker.wake();
+                continue;
+            }
+            Some((_, None)) => continue,
+            None => break,
+        };
+
+        (timer.callback)();
+
+        if let Some(period) = timer.period {
+            interrupts::without_interrupts(|| {
+                let mut timers = TIMERS.lock();
+                if let Some((_, false)) = timers.running.take() {
+                    timers.entries.insert(deadline.id, Entry::Callback(timer));
+                    timers.insert(deadline.tick + period, deadline.id);
+                }
+            });
+        }
+    }
+}
+
+/// Advances the tick counter. This is the timer IRQ handler; it only raises
+/// the bottom half when a deadline expired.
+fn tick() -> IrqReturn {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if now >= NEXT_DEADLINE.load(Ordering::Acquire) {
+        if let Some(bottom_half) = BOTTOM_HALF.r#try() {
+            bottom_half.raise();
+        }
+    }
+    IrqReturn::Handled
+}
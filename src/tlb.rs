@@ -0,0 +1,148 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::interrupts::{self, IrqLine, IrqReturn};
+use crate::{apic, cpu, cpu_local};
+
+/// The line shootdown IPIs are sent on.
+pub const SHOOTDOWN_LINE: IrqLine = IrqLine::Apic(interrupts::APIC_VECTOR_COUNT - 1);
+
+/// Ranges longer than this are flushed by reloading CR3 instead of one
+/// `invlpg` per page.
+pub const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+/// Number of pages in a request that flushes the complete TLB.
+const ALL_PAGES: u64 = u64::max_value();
+
+cpu_local! {
+    /// Physical address of the level 4 table each CPU uses, or 0 for CPUs
+    /// that are not online yet.
+    static ACTIVE_TABLE: AtomicU64 = AtomicU64::new(0);
+}
+
+cpu_local! {
+    /// Set by the initiator of a shootdown, and cleared by the target once
+    /// it flushed its TLB.
+    static PENDING: AtomicBool = AtomicBool::new(false);
+}
+
+/// Serialises shootdowns, so that the request stays unchanged until all
+/// targets acknowledged it.
+static SHOOTDOWN: spin::Mutex<()> = spin::Mutex::new(());
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+static INITIALISED: AtomicBool = AtomicBool::new(false);
+
+/// Registers the shootdown IPI handler and records the address space of the
+/// bootstrap processor. Must be called before application processors are
+/// started.
+pub fn init() {
+    if INITIALISED.load(Ordering::Relaxed) {
+        return;
+    }
+    let handle = interrupts::register_irq(SHOOTDOWN_LINE, handle_ipi)
+        .expect("TLB shootdown line in use");
+    // the handler stays registered for as long as the kernel runs
+    core::mem::forget(handle);
+    init_cpu();
+    INITIALISED.store(true, Ordering::Release);
+}
+
+/// Records the address space of the current CPU, so that shootdowns for it
+/// reach this CPU. Called by every application processor once it uses the
+/// kernel page tables.
+pub(crate) fn init_cpu() {
+    ACTIVE_TABLE.get().store(active_table(), Ordering::Release);
+}
+
+/// Invalidates the translations of `count` pages starting at `start` on
+/// every CPU that uses the current address space, and waits until all of
+/// them did.
+///
+/// Must not be called with a lock held that interrupt handlers take, as
+/// other CPUs could be spinning on it with interrupts disabled and never
+/// acknowledge the shootdown.
+pub fn flush(start: Page<Size4KiB>, count: u64) {
+    shootdown(start.start_address().as_u64(), count);
+}
+
+/// Invalidates all translations on every CPU that uses the current address
+/// space, and waits until all of them did.
+pub fn flush_all() {
+    shootdown(0, ALL_PAGES);
+}
+
+fn shootdown(start: u64, pages: u64) {
+    flush_local(start, pages);
+    if !INITIALISED.load(Ordering::Acquire) || cpu::count() == 1 {
+        return;
+    }
+    // the initiator must not be preempted while other CPUs wait for it
+    x86_64::instructions::interrupts::without_interrupts(|| send_and_wait(start, pages));
+}
+
+fn send_and_wait(start: u64, pages: u64) {
+    // a CPU waiting here may be the target of the shootdown in progress,
+    // so it keeps serving requests meanwhile
+    let _shootdown = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        handle_pending();
+        core::sync::atomic::spin_loop_hint();
+    };
+    REQUEST_START.store(start, Ordering::Relaxed);
+    REQUEST_PAGES.store(pages, Ordering::Relaxed);
+
+    let table = active_table();
+    let current = cpu::id();
+    let targets = (0..cpu::MAX_CPUS).filter(|&target| {
+        target != current && ACTIVE_TABLE.get_for(target).load(Ordering::Acquire) == table
+    });
+    for target in targets.clone() {
+        PENDING.get_for(target).store(true, Ordering::Release);
+        if let Some(apic_id) = cpu::apic_id(target) {
+            apic::send_ipi(apic_id, SHOOTDOWN_LINE.vector());
+        }
+    }
+    for target in targets {
+        while PENDING.get_for(target).load(Ordering::Acquire) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+fn handle_ipi() -> IrqReturn {
+    handle_pending();
+    IrqReturn::Handled
+}
+
+/// Carries out the shootdown request for the current CPU, if there is one.
+fn handle_pending() {
+    let pending = PENDING.get();
+    if pending.load(Ordering::Acquire) {
+        flush_local(
+            REQUEST_START.load(Ordering::Relaxed),
+            REQUEST_PAGES.load(Ordering::Relaxed),
+        );
+        pending.store(false, Ordering::Release);
+    }
+}
+
+fn flush_local(start: u64, pages: u64) {
+    if pages > FULL_FLUSH_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            tlb::flush(VirtAddr::new(start + page * 4096));
+        }
+    }
+}
+
+fn active_table() -> u64 {
+    let (level_4_table, _) = Cr3::read();
+    level_4_table.start_address().as_u64()
+}
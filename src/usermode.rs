@@ -0,0 +1,166 @@
+use core::cell::Cell;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::interrupts::TrapFrame;
+use crate::{cpu, cpu_local};
+
+// Execution at CPL 3. A kernel thread enters user mode with `enter`, which
+// saves its callee-saved registers on its kernel stack and `iretq`s to the
+// user program. The stack pointer below the saved registers becomes `rsp0`
+// of the TSS, so interrupts from user mode run on the kernel stack right
+// below them, and going back to the kernel only needs to load `rsp0` and
+// pop the registers.
+//
+// The program leaves user mode through the exit gate, or by raising an
+// exception. Other interrupts are handled as usual and may switch threads
+// meanwhile; the scheduler keeps `rsp0` per thread.
+//
+// `enter_user_mode` executes `swapgs` right before `iretq`, so the user
+// program runs with its own GS base, which starts out as zero, while
+// `IA32_KERNEL_GS_BASE` keeps the per-CPU block. The interrupt entry code
+// swaps them back, and leaving user mode through `resume_kernel` keeps
+// them that way.
+
+/// Vector of the exit gate. It is the only vector user code may raise with
+/// `int`, passing its exit code in `%rdi`.
+pub const EXIT_VECTOR: u8 = 0x80;
+
+// `%gs:52` is `privilege_stack_table[0]` of the TSS, at `cpu::RSP0_OFFSET`.
+global_asm!(
+    r#"
+.global enter_user_mode
+enter_user_mode:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, %gs:52
+    push %rcx
+    push %rsi
+    pushq $0x202
+    push %rdx
+    push %rdi
+    xor %eax, %eax
+    xor %ebx, %ebx
+    xor %ecx, %ecx
+    xor %edx, %edx
+    xor %esi, %esi
+    xor %edi, %edi
+    xor %ebp, %ebp
+    xor %r8d, %r8d
+    xor %r9d, %r9d
+    xor %r10d, %r10d
+    xor %r11d, %r11d
+    xor %r12d, %r12d
+    xor %r13d, %r13d
+    xor %r14d, %r14d
+    xor %r15d, %r15d
+    swapgs
+    iretq
+
+.global resume_kernel
+resume_kernel:
+    mov %gs:52, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+"#
+);
+
+extern "C" {
+    /// Saves the callee-saved registers, points `rsp0` below them and
+    /// enters user mode at `entry` with interrupts enabled. Returns once
+    /// `resume_kernel` is called, with interrupts disabled.
+    fn enter_user_mode(entry: u64, user_stack: u64, code_selector: u64, stack_selector: u64);
+
+    /// Returns from `enter_user_mode` of the current thread, discarding
+    /// the stack of the running interrupt handler.
+    fn resume_kernel() -> !;
+}
+
+/// Why a user program returned control to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The program raised the exit gate with this code.
+    Exited(u64),
+    /// The program raised an exception other than a page fault.
+    Exception {
+        vector: u8,
+        error_code: Option<u64>,
+        instruction_pointer: VirtAddr,
+    },
+    /// The program accessed `address`, which is not mapped for it.
+    PageFault {
+        address: VirtAddr,
+        error_code: PageFaultErrorCode,
+        instruction_pointer: VirtAddr,
+    },
+}
+
+cpu_local! {
+    /// The exit of the user program each CPU just left, handed from the
+    /// interrupt handler to `enter` with interrupts disabled.
+    static EXIT: Cell<Option<UserExit>> = Cell::new(None);
+}
+
+/// Runs the code at `entry` in ring 3 on `user_stack`, until it raises the
+/// exit gate or an exception. The thread may be preempted meanwhile.
+///
+/// Both must be mapped with `USER_ACCESSIBLE`; a missing mapping is
+/// reported as a page fault.
+///
+/// This function is unsafe because no kernel data may be mapped user
+/// accessible.
+pub unsafe fn enter(entry: VirtAddr, user_stack: VirtAddr) -> UserExit {
+    assert!(
+        !crate::interrupts::in_interrupt(),
+        "entering user mode from an interrupt handler"
+    );
+    let (code_selector, stack_selector) = cpu::current().gdt().user_selectors();
+    let interrupts_enabled = interrupts::are_enabled();
+    // `iretq` enables them again, and leaving user mode disables them
+    interrupts::disable();
+    enter_user_mode(
+        entry.as_u64(),
+        user_stack.as_u64(),
+        u64::from(code_selector.0),
+        u64::from(stack_selector.0),
+    );
+    let exit = EXIT
+        .with(Cell::take)
+        .expect("left user mode without an exit");
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+/// Returns `true` if the interrupted code ran in user mode.
+pub(crate) fn from_user_mode(stack_frame: &InterruptStackFrameValue) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Returns from `enter` of the current thread with `exit`. Must be called
+/// by an exception handler that interrupted user mode.
+pub(crate) fn leave(exit: UserExit) -> ! {
+    EXIT.with(|cell| cell.set(Some(exit)));
+    unsafe { resume_kernel() }
+}
+
+/// The handler of `EXIT_VECTOR`, which runs on the kernel stack of the
+/// thread.
+pub(crate) fn exit_gate(frame: &TrapFrame) -> ! {
+    assert!(
+        from_user_mode(&frame.stack_frame),
+        "exit gate raised in kernel mode"
+    );
+    leave(UserExit::Exited(frame.registers.rdi))
+}
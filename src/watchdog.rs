@@ -0,0 +1,219 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+use crate::interrupts::{self as irq, InterruptIndex, IrqReturn, TrapFrame};
+use crate::sync::IrqSafeMutex;
+use crate::{apic, serial_println, time, timer};
+
+/// The kernel is considered soft-locked if it did not reach the idle loop
+/// for this long while timer interrupts kept arriving.
+const SOFT_LOCKUP_SECS: u64 = 10;
+/// The kernel is considered hard-locked if no timer interrupt arrived for
+/// this long, which means interrupts stayed disabled.
+const HARD_LOCKUP_SECS: u64 = 3;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Unhalted core cycles, counted in ring 0 and 3, raising an interrupt on
+/// overflow.
+const PERFEVTSEL_UNHALTED_CYCLES: u64 = 0x3c | (1 << 16) | (1 << 17) | (1 << 20) | (1 << 22);
+
+/// The largest period a PMC write can express: only the low 32 bits are
+/// written and then sign-extended.
+const MAX_NMI_PERIOD_CYCLES: u64 = 0x7fff_ffff;
+
+/// Tick at which the kernel last reached the idle loop.
+static LAST_TOUCH_TICK: AtomicU64 = AtomicU64::new(0);
+static SOFT_LOCKUP_REPORTED: AtomicBool = AtomicBool::new(false);
+
+static NMI_WATCHDOG: AtomicBool = AtomicBool::new(false);
+static NMI_PERIOD_CYCLES: AtomicU64 = AtomicU64::new(0);
+/// Timer tick count seen by the last watchdog NMI, and the TSC value at
+/// which it last changed.
+static LAST_NMI_TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_PROGRESS_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the soft lockup check on the timer line and, if the CPU has
+/// architectural performance monitoring version 2 and the local APIC is
+/// enabled, the NMI-based hard lockup detector. Returns `true` if the latter
+/// is running.
+///
+/// The local APIC timer cannot deliver NMIs, so the hard lockup detector
+/// relies on a performance counter overflowing into an NMI. Version 2 adds
+/// the global overflow status, which tells its NMIs apart from others.
+pub fn init() -> bool {
+    touch();
+    irq::register_irq(InterruptIndex::Timer.line(), check_soft_lockup)
+        .expect("failed to register watchdog handler");
+
+    if !apic::is_enabled() || time::tsc_frequency() == 0 {
+        return false;
+    }
+    let perfmon_version = if unsafe { __cpuid(0) }.eax >= 0xa {
+        unsafe { __cpuid(0xa) }.eax & 0xff
+    } else {
+        0
+    };
+    if perfmon_version < 2 {
+        return false;
+    }
+
+    let period = time::tsc_frequency().min(MAX_NMI_PERIOD_CYCLES);
+    NMI_PERIOD_CYCLES.store(period, Ordering::Relaxed);
+    LAST_NMI_TICKS.store(timer::ticks(), Ordering::Relaxed);
+    LAST_PROGRESS_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    NMI_WATCHDOG.store(true, Ordering::Release);
+
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        rearm_counter(period);
+        Msr::new(IA32_PERFEVTSEL0).write(PERFEVTSEL_UNHALTED_CYCLES);
+        Msr::new(IA32_PERF_GLOBAL_CTRL).write(1);
+    }
+    true
+}
+
+/// Records that the kernel made progress. Called from the idle loop.
+pub fn touch() {
+    LAST_TOUCH_TICK.store(timer::ticks(), Ordering::Relaxed);
+    SOFT_LOCKUP_REPORTED.store(false, Ordering::Relaxed);
+}
+
+/// Returns `true` if the NMI-based hard lockup detector is running.
+pub fn nmi_watchdog_enabled() -> bool {
+    NMI_WATCHDOG.load(Ordering::Acquire)
+}
+
+/// Shares the timer line to check that the kernel still reaches the idle
+/// loop. Reports a soft lockup once per stall.
+fn check_soft_lockup() -> IrqReturn {
+    let stalled = timer::ticks().saturating_sub(LAST_TOUCH_TICK.load(Ordering::Relaxed));
+    if stalled >= SOFT_LOCKUP_SECS * timer::TICKS_PER_SECOND
+        && !SOFT_LOCKUP_REPORTED.swap(true, Ordering::Relaxed)
+    {
+        serial_println!("WATCHDOG: soft lockup, no idle loop for {} s",
+            stalled / timer::TICKS_PER_SECOND);
+        print_lock_holders();
+    }
+    // the timer handler itself acknowledges the line
+    IrqReturn::NotHandled
+}
+
+/// Checks for a hard lockup from the NMI handler. Returns `false` if the
+/// NMI was not raised by the watchdog, that is if PMC0 did not overflow.
+pub(crate) fn handle_nmi(frame: &TrapFrame) -> bool {
+    if !nmi_watchdog_enabled() || !pmc0_overflowed() {
+        return false;
+    }
+
+    let ticks = timer::ticks();
+    let now = unsafe { _rdtsc() };
+    if LAST_NMI_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        LAST_PROGRESS_TSC.store(now, Ordering::Relaxed);
+    } else {
+        let stalled = now - LAST_PROGRESS_TSC.load(Ordering::Relaxed);
+        if stalled >= HARD_LOCKUP_SECS * time::tsc_frequency() {
+            report_hard_lockup(stalled / time::tsc_frequency(), frame);
+        }
+    }
+
+    unsafe { rearm_counter(NMI_PERIOD_CYCLES.load(Ordering::Relaxed)) };
+    true
+}
+
+fn pmc0_overflowed() -> bool {
+    unsafe { Msr::new(IA32_PERF_GLOBAL_STATUS).read() } & 1 != 0
+}
+
+/// Resets PMC0 so that it overflows after `period` cycles, clears the
+/// overflow status and unmasks the performance counter LVT entry.
+unsafe fn rearm_counter(period: u64) {
+    Msr::new(IA32_PMC0).write(0u64.wrapping_sub(period));
+    Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+    apic::route_performance_counter_to_nmi();
+}
+
+/// Reports the lockup and halts the CPU for good. It does not panic, as the
+/// panic handler would take locks the locked-up code may hold.
+fn report_hard_lockup(seconds: u64, frame: &TrapFrame) -> ! {
+    let held = held_locks();
+    // the locked-up code may hold the serial port and VGA locks
+    if let LockState::Held(_) = held.serial {
+        unsafe { crate::serial::SERIAL1.force_unlock() };
+    }
+    if let LockState::Held(_) = held.writer {
+        unsafe { crate::vga_buffer::WRITER.force_unlock() };
+    }
+
+    let stack_frame = &frame.stack_frame;
+    serial_println!("WATCHDOG: hard lockup, no timer interrupt for {} s", seconds);
+    serial_println!("Interrupted RIP: {:#x}", stack_frame.instruction_pointer.as_u64());
+    serial_println!("{}", frame.registers);
+    serial_println!("{:#?}", stack_frame);
+    held.print();
+    x86_64::instructions::interrupts::disable();
+    crate::hlt_loop();
+}
+
+/// Which of the global kernel locks were held at the time of the check,
+/// and where, if known.
+struct HeldLocks {
+    writer: LockState,
+    serial: LockState,
+    pics: LockState,
+    allocator: LockState,
+}
+
+#[derive(Clone, Copy)]
+enum LockState {
+    Free,
+    Held(Option<&'static Location<'static>>),
+}
+
+impl LockState {
+    fn of<T>(lock: &IrqSafeMutex<T>) -> LockState {
+        if lock.is_locked() {
+            LockState::Held(lock.owner_location())
+        } else {
+            LockState::Free
+        }
+    }
+}
+
+fn held_locks() -> HeldLocks {
+    HeldLocks {
+        writer: LockState::of(&*crate::vga_buffer::WRITER),
+        serial: LockState::of(&*crate::serial::SERIAL1),
+        pics: LockState::of(&crate::interrupts::PICS),
+        allocator: LockState::of(&crate::ALLOCATOR),
+    }
+}
+
+impl HeldLocks {
+    fn print(&self) {
+        let locks = [
+            ("WRITER", self.writer),
+            ("SERIAL1", self.serial),
+            ("PICS", self.pics),
+            ("ALLOCATOR", self.allocator),
+        ];
+        for (name, state) in locks.iter() {
+            match state {
+                LockState::Free => serial_println!("  {:<10} free", name),
+                LockState::Held(Some(location)) => serial_println!("  {:<10} held at {}", name, location),
+                LockState::Held(None) => serial_println!("  {:<10} held", name),
+            }
+        }
+    }
+}
+
+fn print_lock_holders() {
+    serial_println!("Lock holders:");
+    held_locks().print();
+}
@@ -0,0 +1,302 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::{Cell, UnsafeCell};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Once;
+
+use crate::sync::{IrqSafeMutex, WaitQueue};
+use crate::thread::{self, JoinHandle};
+
+/// Number of worker threads of the system work queue.
+pub const SYSTEM_WORKERS: usize = 2;
+
+static SYSTEM: Once<WorkQueue> = Once::new();
+
+/// Creates the system work queue. Must be called after `scheduler::init`.
+pub fn init() {
+    SYSTEM.call_once(|| WorkQueue::new("system", SYSTEM_WORKERS));
+}
+
+/// Returns the work queue shared by all subsystems.
+///
+/// Panics if `init` was not called yet.
+pub fn system() -> &'static WorkQueue {
+    SYSTEM.r#try().expect("work queues not initialised")
+}
+
+enum WorkFn {
+    Static(fn()),
+    /// A closure queued with `queue_fn`. The `Work` holding it was leaked
+    /// and is freed once the closure ran.
+    Boxed(UnsafeCell<Option<Box<dyn FnOnce() + Send>>>),
+}
+
+/// A function that can be queued on a work queue without allocating, so
+/// that interrupt handlers can defer work to process context.
+///
+/// ```ignore
+/// static RESET: Work = Work::new(reset_device);
+/// workqueue::system().queue(&RESET);
+/// ```
+pub struct Work {
+    func: WorkFn,
+    pending: AtomicBool,
+    /// The next item of the queue this item is on, protected by the lock of
+    /// that queue.
+    next: Cell<Option<&'static Work>>,
+}
+
+// `next` is only accessed with the queue lock held, and a boxed closure is
+// only taken by the worker that removed the item from its queue.
+unsafe impl Sync for Work {}
+
+impl Work {
+    pub const fn new(func: fn()) -> Work {
+        Work {
+            func: WorkFn::Static(func),
+            pending: AtomicBool::new(false),
+            next: Cell::new(None),
+        }
+    }
+
+    fn boxed(closure: Box<dyn FnOnce() + Send>) -> Work {
+        Work {
+            func: WorkFn::Boxed(UnsafeCell::new(Some(closure))),
+            pending: AtomicBool::new(false),
+            next: Cell::new(None),
+        }
+    }
+
+    /// Returns `true` if the item is queued and did not start running yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    fn run(&'static self) {
+        match &self.func {
+            WorkFn::Static(func) => func(),
+            WorkFn::Boxed(closure) => {
+                if let Some(closure) = unsafe { (*closure.get()).take() } {
+                    closure();
+                }
+                // leaked by `queue_fn`, and no longer on any queue
+                unsafe { drop(Box::from_raw(self as *const Work as *mut Work)) };
+            }
+        }
+    }
+}
+
+/// The queued items of a work queue, linked through `Work::next`.
+#[derive(Default)]
+struct List {
+    head: Option<&'static Work>,
+    tail: Option<&'static Work>,
+    /// Number of items the workers are running.
+    running: usize,
+    /// Set when the queue is dropped, to stop the workers.
+    shutdown: bool,
+}
+
+impl List {
+    fn push(&mut self, work: &'static Work) {
+        work.next.set(None);
+        match self.tail {
+            Some(tail) => tail.next.set(Some(work)),
+            None => self.head = Some(work),
+        }
+        self.tail = Some(work);
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        let work = self.head?;
+        self.head = work.next.take();
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        Some(work)
+    }
+
+    fn remove(&mut self, work: &'static Work) -> bool {
+        let mut previous: Option<&'static Work> = None;
+        let mut current = self.head;
+        while let Some(item) = current {
+            if ptr::eq(item, work) {
+                let next = item.next.take();
+                match previous {
+                    Some(previous) => previous.next.set(next),
+                    None => self.head = next,
+                }
+                if next.is_none() {
+                    self.tail = previous;
+                }
+                return true;
+            }
+            previous = current;
+            current = item.next.get();
+        }
+        false
+    }
+
+    fn is_idle(&self) -> bool {
+        self.head.is_none() && self.running == 0
+    }
+}
+
+struct Inner {
+    name: &'static str,
+    list: IrqSafeMutex<List>,
+    /// The workers waiting for items.
+    available: WaitQueue,
+    /// The threads waiting in `flush`.
+    idle: WaitQueue,
+    completed: AtomicU64,
+}
+
+/// A named queue of work run by its own kernel threads. Items can be queued
+/// from any context, including interrupt handlers, and run in process
+/// context, where they may block and allocate.
+///
+/// At most `max_active` items run at the same time, one per worker thread.
+/// Items start in the order they were queued.
+pub struct WorkQueue {
+    inner: Arc<Inner>,
+    workers: Vec<JoinHandle>,
+}
+
+impl WorkQueue {
+    /// Creates a queue with `max_active` worker threads.
+    ///
+    /// Panics if `max_active` is zero or no kernel stack could be allocated.
+    pub fn new(name: &'static str, max_active: usize) -> WorkQueue {
+        assert!(max_active > 0, "work queue without workers");
+        let inner = Arc::new(Inner {
+            name,
+            list: IrqSafeMutex::new(List::default()),
+            available: WaitQueue::new(),
+            idle: WaitQueue::new(),
+            completed: AtomicU64::new(0),
+        });
+        let workers = (0..max_active)
+            .map(|_| {
+                let inner = inner.clone();
+                thread::spawn(move || worker(&inner))
+            })
+            .collect();
+        WorkQueue { inner, workers }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.inner.name
+    }
+
+    pub fn max_active(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Queues `work`, unless it is pending already. Returns `true` if it was
+    /// queued. An item that started running can be queued again; on a queue
+    /// with several workers it may then run twice at the same time.
+    pub fn queue(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.inner.list.lock().push(work);
+        self.inner.available.notify_one();
+        true
+    }
+
+    /// Queues a closure. This allocates, so interrupt handlers should queue
+    /// a static `Work` instead.
+    pub fn queue_fn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let work: &'static Work = Box::leak(Box::new(Work::boxed(Box::new(f))));
+        self.queue(work);
+    }
+
+    /// Removes `work` from the queue if it did not start running yet.
+    /// Returns `true` if it was removed.
+    pub fn cancel(&self, work: &'static Work) -> bool {
+        let mut list = self.inner.list.lock();
+        let removed = list.remove(work);
+        if removed {
+            work.pending.store(false, Ordering::Release);
+        }
+        removed
+    }
+
+    /// Blocks until the queue is empty and no item is running, which
+    /// includes the items queued by the running ones.
+    ///
+    /// Must not be called from an item of the same queue.
+    pub fn flush(&self) {
+        let list = &self.inner.list;
+        self.inner.idle.wait_until(|| list.lock().is_idle());
+    }
+
+    /// Returns the number of items waiting to be run.
+    pub fn pending(&self) -> usize {
+        let list = self.inner.list.lock();
+        let mut count = 0;
+        let mut current = list.head;
+        while let Some(item) = current {
+            count += 1;
+            current = item.next.get();
+        }
+        count
+    }
+
+    /// Returns the number of items that ran to completion.
+    pub fn completed(&self) -> u64 {
+        self.inner.completed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WorkQueue {
+    /// Runs the queued items and stops the workers.
+    fn drop(&mut self) {
+        self.flush();
+        self.inner.list.lock().shutdown = true;
+        self.inner.available.notify_all();
+        for worker in self.workers.drain(..) {
+            worker.join();
+        }
+    }
+}
+
+fn worker(inner: &Inner) {
+    loop {
+        let mut next = None;
+        // checked with the wait queue locked, so that an item queued after
+        // the check notifies this worker
+        inner.available.wait_until(|| {
+            let mut list = inner.list.lock();
+            if let Some(work) = list.pop() {
+                work.pending.store(false, Ordering::Release);
+                list.running += 1;
+                next = Some(work);
+                true
+            } else {
+                list.shutdown
+            }
+        });
+        let work = match next {
+            Some(work) => work,
+            None => return,
+        };
+
+        work.run();
+        inner.completed.fetch_add(1, Ordering::Relaxed);
+        let idle = {
+            let mut list = inner.list.lock();
+            list.running -= 1;
+            list.is_idle()
+        };
+        if idle {
+            inner.idle.notify_all();
+        }
+    }
+}
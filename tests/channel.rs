@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use curi_os::channel::{mpsc, spsc, RecvError, SendError, TryRecvError};
+use curi_os::scheduler;
+use curi_os::sync::IrqSafeMutex;
+use curi_os::task::{executor::Executor, Task};
+use curi_os::thread;
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spsc_counts_dropped() {
+    serial_print!("spsc_counts_dropped... ");
+    let (mut sender, mut receiver) = spsc::channel(3);
+    assert_eq!(receiver.capacity(), 4);
+    for n in 0..4 {
+        assert_eq!(sender.send(n), Ok(()));
+    }
+    assert_eq!(sender.send(4), Err(SendError::Full(4)));
+    assert_eq!(receiver.len(), 4);
+    assert_eq!(receiver.dropped(), 1);
+
+    // wrap around a few times
+    for n in 0..10 {
+        assert_eq!(receiver.try_recv(), Ok(n));
+        assert_eq!(sender.send(n + 4), Ok(()));
+    }
+    assert_eq!(receiver.len(), 4);
+    assert_eq!(sender.dropped(), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn closed_by_either_side() {
+    serial_print!("closed_by_either_side... ");
+    let (mut sender, mut receiver) = spsc::channel(4);
+    sender.send(1).unwrap();
+    drop(sender);
+    assert!(receiver.is_closed());
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    assert_eq!(receiver.recv(), Err(RecvError));
+
+    let (sender, mut receiver) = mpsc::channel(4);
+    let other = sender.clone();
+    drop(sender);
+    assert!(!receiver.is_closed());
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    receiver.close();
+    assert!(other.is_closed());
+    assert_eq!(other.send(2), Err(SendError::Closed(2)));
+    assert_eq!(receiver.dropped(), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mpsc_receives_from_threads() {
+    serial_print!("mpsc_receives_from_threads... ");
+    let (sender, mut receiver) = mpsc::channel(4);
+
+    // the channel is smaller than the number of values, so senders retry
+    // until the receiver made room
+    let handles: Vec<_> = (0..3)
+        .map(|n| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for value in 0..10 {
+                    let mut value = n * 10 + value;
+                    while let Err(error) = sender.send(value) {
+                        value = error.into_inner();
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut received = Vec::new();
+    while let Ok(value) = receiver.recv() {
+        received.push(value);
+    }
+    for handle in handles {
+        handle.join();
+    }
+    received.sort_unstable();
+    assert_eq!(received, (0..30).collect::<Vec<_>>());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sent_from_interrupt() {
+    use curi_os::interrupts::{self, IrqLine, IrqReturn};
+
+    serial_print!("sent_from_interrupt... ");
+    static SENDER: IrqSafeMutex<Option<spsc::Sender<u8>>> = IrqSafeMutex::new(None);
+
+    fn send() -> IrqReturn {
+        if let Some(sender) = SENDER.lock().as_mut() {
+            let _ = sender.send(42);
+        }
+        IrqReturn::Handled
+    }
+
+    let (sender, mut receiver) = spsc::channel(4);
+    *SENDER.lock() = Some(sender);
+    let handle = interrupts::register_irq(IrqLine::Apic(4), send).expect("line in use");
+    let waiter = thread::spawn(move || assert_eq!(receiver.recv(), Ok(42)));
+    thread::yield_now();
+    unsafe { asm!("int $$0x34" :::: "volatile") };
+    waiter.join();
+    handle.unregister();
+    SENDER.lock().take();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn received_by_task() {
+    use futures_util::stream::StreamExt;
+
+    serial_print!("received_by_task... ");
+    let (sender, mut receiver) = mpsc::channel(8);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        assert_eq!(receiver.recv_async().await, Ok(1));
+        assert_eq!(receiver.next().await, Some(2));
+        assert_eq!(receiver.next().await, None);
+    }));
+    executor.run_ready_tasks();
+    assert!(!executor.is_empty());
+
+    sender.send(1).unwrap();
+    sender.send(2).unwrap();
+    drop(sender);
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+    serial_println!("[ok]");
+}
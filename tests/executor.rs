@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use curi_os::task::{executor::Executor, Task};
+use curi_os::timer;
+use curi_os::{serial_print, serial_println};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialisation failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+/// Runs the executor until all tasks completed, running expired timer
+/// callbacks in between and failing the test if that takes longer than a
+/// second.
+fn run_to_completion(executor: &mut Executor) {
+    let deadline = timer::ticks() + timer::TICKS_PER_SECOND;
+    loop {
+        executor.run_ready_tasks();
+        if executor.is_empty() {
+            return;
+        }
+        assert!(timer::ticks() < deadline, "timed out");
+        timer::run_expired();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Returns `Pending` once, waking itself before doing so.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn spawned_tasks_complete() {
+    serial_print!("spawned_tasks_complete... ");
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn add_one() -> usize {
+        1
+    }
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(async {
+            COMPLETED.fetch_add(add_one().await, Ordering::SeqCst);
+        }));
+    }
+    run_to_completion(&mut executor);
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn self_waking_task() {
+    serial_print!("self_waking_task... ");
+    static POLLED_AGAIN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        YieldNow(false).await;
+        POLLED_AGAIN.store(true, Ordering::SeqCst);
+    }));
+    run_to_completion(&mut executor);
+    assert!(POLLED_AGAIN.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn woken_more_often_than_queue_capacity() {
+    serial_print!("woken_more_often_than_queue_capacity... ");
+
+    /// Wakes itself many times before returning `Pending` once.
+    struct WakeRepeatedly(bool);
+
+    impl Future for WakeRepeatedly {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            for _ in 0..1000 {
+                context.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WakeRepeatedly(false)));
+    executor.spawn(Task::new(WakeRepeatedly(false)));
+    run_to_completion(&mut executor);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn woken_by_timer() {
+    serial_print!("woken_by_timer... ");
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+    /// Completes once the timer callback fired.
+    struct WaitForTimer;
+
+    impl Future for WaitForTimer {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            *WAKER.lock() = Some(context.waker().clone());
+            if FIRED.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    timer::after(Duration::from_millis(20), || {
+        FIRED.store(true, Ordering::SeqCst);
+        if let Some(waker) = WAKER.lock().take() {
+            waker.wake();
+        }
+    });
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WaitForTimer));
+    run_to_completion(&mut executor);
+    assert!(FIRED.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
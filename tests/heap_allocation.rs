@@ -64,3 +64,18 @@ fn many_boxes() {
     }
     serial_println!("[ok]");
 }
+
+#[test_case]
+fn heap_page_walk() {
+    use curi_os::allocator::{HEAP_SIZE, HEAP_START};
+    use curi_os::memory::walk_page_tables;
+    use x86_64::VirtAddr;
+
+    serial_print!("heap_page_walk... ");
+    let heap = walk_page_tables(VirtAddr::new(HEAP_START as u64));
+    assert!(heap.is_mapped());
+    let guard = walk_page_tables(VirtAddr::new((HEAP_START + HEAP_SIZE) as u64));
+    assert!(!guard.is_mapped());
+    assert!(guard.missing_level.is_some());
+    serial_println!("[ok]");
+}
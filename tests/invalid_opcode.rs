@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(panic_info_message)]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use curi_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+const MESSAGE: &str = "EXCEPTION: INVALID OPCODE (#UD)";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode... ");
+    curi_os::init();
+
+    // must reach the #UD handler instead of escalating to a double fault
+    unsafe { asm!("ud2" :::: "volatile") };
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after invalid opcode");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = PanicMessage { len: 0, buffer: [0; 64] };
+    if let Some(args) = info.message() {
+        let _ = write!(&mut message, "{}", args);
+    }
+
+    if message.as_str() == MESSAGE {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected panic: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+/// Captures the beginning of a panic message for comparison.
+struct PanicMessage {
+    len: usize,
+    buffer: [u8; 64],
+}
+
+impl PanicMessage {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
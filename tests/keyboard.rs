@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use curi_os::task::keyboard::{KeyEventStream, ScancodeStream};
+use curi_os::{bottom_half, serial_print, serial_println};
+use futures_util::stream::Stream;
+use futures_util::task::noop_waker_ref;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+/// Raises the keyboard interrupt, which reads a scancode from the
+/// controller, and hands it to the streams.
+fn raise_keyboard_irq() {
+    unsafe { asm!("int $$0x21" :::: "volatile") };
+    bottom_half::run_pending();
+}
+
+fn poll<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+    let mut context = Context::from_waker(noop_waker_ref());
+    Pin::new(stream).poll_next(&mut context)
+}
+
+#[test_case]
+fn every_stream_receives_scancodes() {
+    serial_print!("every_stream_receives_scancodes... ");
+    let mut first = ScancodeStream::new();
+    let mut second = ScancodeStream::new();
+    // a key event stream may exist at the same time
+    let _events = KeyEventStream::new();
+    assert_eq!(poll(&mut first), Poll::Pending);
+    assert_eq!(poll(&mut second), Poll::Pending);
+
+    raise_keyboard_irq();
+    let scancode = match poll(&mut first) {
+        Poll::Ready(Some(scancode)) => scancode,
+        poll => panic!("no scancode: {:?}", poll),
+    };
+    assert_eq!(poll(&mut second), Poll::Ready(Some(scancode)));
+    assert_eq!(poll(&mut first), Poll::Pending);
+    assert_eq!(poll(&mut second), Poll::Pending);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,220 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use curi_os::interrupts::{self, IrqLine, IrqReturn};
+use curi_os::rcu::{self, RcuCell};
+use curi_os::{apic, cpu, scheduler, smp, thread, timer, workqueue};
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+    workqueue::init();
+    assert!(apic::init(), "no local APIC found");
+    smp::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+/// The line the tests interrupt the application processors on.
+const READER_LINE: IrqLine = IrqLine::Apic(7);
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// A value whose fields are only consistent until it is dropped.
+struct Version {
+    number: u64,
+    check: u64,
+}
+
+impl Version {
+    fn new(number: u64) -> Version {
+        Version {
+            number,
+            check: !number,
+        }
+    }
+
+    fn assert_valid(&self) {
+        assert_eq!(self.check, !self.number, "read a freed version");
+    }
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        self.check = self.number;
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Interrupts every application processor on `READER_LINE`.
+fn interrupt_other_cpus() {
+    for cpu in 1..cpu::count() {
+        if let Some(apic_id) = cpu::apic_id(cpu) {
+            apic::send_ipi(apic_id, READER_LINE.vector());
+        }
+    }
+}
+
+#[test_case]
+fn readers_race_writer() {
+    serial_print!("readers_race_writer... ");
+    static CELL: AtomicPtr<RcuCell<Version>> = AtomicPtr::new(core::ptr::null_mut());
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static READS: AtomicUsize = AtomicUsize::new(0);
+    const UPDATES: u64 = 200;
+
+    fn read_on_interrupt() -> IrqReturn {
+        let cell = unsafe { &*CELL.load(Ordering::SeqCst) };
+        let guard = rcu::read_lock();
+        cell.read(&guard).assert_valid();
+        READS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    }
+
+    let cell: &'static RcuCell<Version> = Box::leak(Box::new(RcuCell::new(Version::new(0))));
+    CELL.store(cell as *const _ as *mut _, Ordering::SeqCst);
+    let handle = interrupts::register_irq(READER_LINE, read_on_interrupt).expect("line in use");
+    let dropped = DROPPED.load(Ordering::SeqCst);
+
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut last = 0;
+                let mut reads = 0u64;
+                while !STOP.load(Ordering::SeqCst) {
+                    let guard = rcu::read_lock();
+                    let version = cell.read(&guard);
+                    version.assert_valid();
+                    assert!(version.number >= last, "versions went backwards");
+                    last = version.number;
+                    drop(guard);
+                    reads += 1;
+                    if reads % 16 == 0 {
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+    let writer = thread::spawn(move || {
+        for number in 1..=UPDATES {
+            cell.update(Version::new(number));
+            interrupt_other_cpus();
+            if number % 8 == 0 {
+                thread::yield_now();
+            }
+        }
+    });
+
+    writer.join();
+    STOP.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join();
+    }
+    rcu::barrier();
+    handle.unregister();
+    assert_eq!(cell.read(&rcu::read_lock()).number, UPDATES);
+    assert_eq!(DROPPED.load(Ordering::SeqCst) - dropped, UPDATES as usize);
+    if cpu::count() > 1 {
+        assert!(READS.load(Ordering::Relaxed) > 0);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn synchronize_waits_for_readers() {
+    serial_print!("synchronize_waits_for_readers... ");
+    static ENTERED: AtomicBool = AtomicBool::new(false);
+    static LEAVE: AtomicBool = AtomicBool::new(false);
+    static SYNCHRONIZED: AtomicBool = AtomicBool::new(false);
+
+    /// Stays in a read-side critical section until told to leave, or for at
+    /// most a second.
+    fn hold_reader() -> IrqReturn {
+        let guard = rcu::read_lock();
+        ENTERED.store(true, Ordering::SeqCst);
+        let deadline = timer::ticks() + timer::TICKS_PER_SECOND;
+        while !LEAVE.load(Ordering::SeqCst) && timer::ticks() < deadline {
+            core::sync::atomic::spin_loop_hint();
+        }
+        drop(guard);
+        IrqReturn::Handled
+    }
+
+    if cpu::count() < 2 {
+        serial_println!("[skipped, single CPU]");
+        return;
+    }
+    let handle = interrupts::register_irq(READER_LINE, hold_reader).expect("line in use");
+    apic::send_ipi(cpu::apic_id(1).unwrap(), READER_LINE.vector());
+    while !ENTERED.load(Ordering::SeqCst) {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    let waiter = thread::spawn(|| {
+        rcu::synchronize_rcu();
+        SYNCHRONIZED.store(true, Ordering::SeqCst);
+    });
+    for _ in 0..10 {
+        thread::yield_now();
+    }
+    assert!(!SYNCHRONIZED.load(Ordering::SeqCst));
+
+    LEAVE.store(true, Ordering::SeqCst);
+    waiter.join();
+    assert!(SYNCHRONIZED.load(Ordering::SeqCst));
+    handle.unregister();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn callbacks_run_after_grace_period() {
+    serial_print!("callbacks_run_after_grace_period... ");
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    let grace_periods = rcu::grace_periods();
+    {
+        // queueing from inside a read-side critical section is fine
+        let _guard = rcu::read_lock();
+        for _ in 0..3 {
+            rcu::call_rcu(|| {
+                RAN.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(RAN.load(Ordering::SeqCst), 0);
+    }
+    rcu::barrier();
+    assert_eq!(RAN.load(Ordering::SeqCst), 3);
+    assert!(rcu::grace_periods() > grace_periods);
+
+    let cell = RcuCell::new(Version::new(1));
+    let old = cell.replace(Version::new(2));
+    assert_eq!(old.number, 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use curi_os::{apic, cpu, smp, tlb};
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+/// CPUs online as reported by `smp::init`.
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    assert!(apic::init(), "no local APIC found");
+    STARTED.store(smp::init(), Ordering::SeqCst);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_cpus_online() {
+    serial_print!("all_cpus_online... ");
+    // the tests run with `-smp 4`
+    assert_eq!(STARTED.load(Ordering::SeqCst), 4);
+    assert_eq!(cpu::count(), 4);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn bsp_is_cpu_zero() {
+    serial_print!("bsp_is_cpu_zero... ");
+    assert_eq!(cpu::id(), 0);
+    assert_eq!(cpu::apic_id(0), Some(apic::id()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn apic_ids_are_distinct() {
+    serial_print!("apic_ids_are_distinct... ");
+    for a in 0..cpu::count() {
+        for b in 0..a {
+            assert_ne!(cpu::apic_id(a), cpu::apic_id(b));
+        }
+    }
+    assert_eq!(cpu::apic_id(cpu::MAX_CPUS), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unmap_shoots_down_tlbs() {
+    use curi_os::interrupts::{interrupt_count, total_interrupt_count};
+    use curi_os::memory;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    serial_print!("unmap_shoots_down_tlbs... ");
+    let vector = tlb::SHOOTDOWN_LINE.vector();
+    let before = total_interrupt_count(vector);
+    let before_cpu_1 = interrupt_count(1, vector);
+
+    let page = Page::containing_address(VirtAddr::new(0x_6666_0000_0000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::map_pages(page, 2, flags).expect("mapping failed");
+    // mapping needs no shootdown, but changing and removing mappings do
+    assert_eq!(total_interrupt_count(vector), before);
+    unsafe { page.start_address().as_mut_ptr::<u64>().write_volatile(42) };
+    memory::protect_pages(page, 2, PageTableFlags::PRESENT).expect("protecting failed");
+    memory::unmap_pages(page, 2);
+
+    // every other CPU acknowledged both shootdowns before they returned
+    let others = cpu::count() as u64 - 1;
+    assert_eq!(total_interrupt_count(vector), before + 2 * others);
+    assert_eq!(interrupt_count(1, vector), before_cpu_1 + 2);
+    assert!(!memory::walk_page_tables(page.start_address()).is_mapped());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,226 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use curi_os::scheduler;
+use curi_os::sync::{Condvar, Mutex, RwLock, Semaphore, WaitQueue};
+use curi_os::thread;
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_excludes() {
+    serial_print!("mutex_excludes... ");
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+
+    // yielding inside the critical section makes the other threads contend
+    // for the lock
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..10 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    thread::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*COUNTER.lock(), 40);
+    assert!(!COUNTER.is_locked());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wait_queue_is_fifo() {
+    serial_print!("wait_queue_is_fifo... ");
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    let mut handles = Vec::new();
+    for n in 0..3 {
+        handles.push(thread::spawn(move || {
+            QUEUE.wait_if(|| true);
+            ORDER.lock().push(n);
+        }));
+        while QUEUE.len() != n + 1 {
+            thread::yield_now();
+        }
+    }
+    for _ in 0..3 {
+        assert!(QUEUE.notify_one());
+        thread::yield_now();
+    }
+    for handle in handles {
+        handle.join();
+    }
+    assert!(!QUEUE.notify_one());
+    assert_eq!(*ORDER.lock(), [0, 1, 2]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    serial_print!("semaphore_limits_concurrency... ");
+    static PERMITS: Semaphore = Semaphore::new(2);
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+
+    let handles: Vec<_> = (0..5)
+        .map(|_| {
+            thread::spawn(|| {
+                PERMITS.acquire();
+                let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+                if inside > MAX_INSIDE.load(Ordering::SeqCst) {
+                    MAX_INSIDE.store(inside, Ordering::SeqCst);
+                }
+                thread::yield_now();
+                INSIDE.fetch_sub(1, Ordering::SeqCst);
+                PERMITS.release();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(MAX_INSIDE.load(Ordering::SeqCst), 2);
+    assert_eq!(PERMITS.available_permits(), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn condvar_notifies() {
+    serial_print!("condvar_notifies... ");
+    static READY: Mutex<bool> = Mutex::new(false);
+    static CHANGED: Condvar = Condvar::new();
+
+    let waiter = thread::spawn(|| {
+        let ready = CHANGED.wait_while(READY.lock(), |ready| !*ready);
+        assert!(*ready);
+    });
+    thread::yield_now();
+    *READY.lock() = true;
+    CHANGED.notify_all();
+    waiter.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rwlock_shares_readers() {
+    serial_print!("rwlock_shares_readers... ");
+    static LOCK: RwLock<usize> = RwLock::new(0);
+    static READERS_IN: AtomicUsize = AtomicUsize::new(0);
+
+    // both readers hold the lock at once, and the writer only gets in
+    // once they are done
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(|| {
+                let value = LOCK.read();
+                READERS_IN.fetch_add(1, Ordering::SeqCst);
+                while READERS_IN.load(Ordering::SeqCst) < 2 {
+                    thread::yield_now();
+                }
+                assert_eq!(*value, 0);
+            })
+        })
+        .collect();
+    while READERS_IN.load(Ordering::SeqCst) < 2 {
+        thread::yield_now();
+    }
+    let writer = thread::spawn(|| {
+        let mut value = LOCK.write();
+        assert_eq!(LOCK.reader_count(), 0);
+        *value += 1;
+    });
+    for reader in readers {
+        reader.join();
+    }
+    writer.join();
+    assert_eq!(*LOCK.read(), 1);
+    assert!(!LOCK.is_write_locked());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn rwlock_is_fifo() {
+    serial_print!("rwlock_is_fifo... ");
+    static LOCK: RwLock<usize> = RwLock::new(0);
+
+    // a writer queued behind a reader gets the lock before a reader that
+    // arrives after it
+    let reader = LOCK.read();
+    let writer = thread::spawn(|| *LOCK.write() += 1);
+    while LOCK.waiting_threads() != 1 {
+        thread::yield_now();
+    }
+    let late_reader = thread::spawn(|| assert_eq!(*LOCK.read(), 1));
+    while LOCK.waiting_threads() != 2 {
+        thread::yield_now();
+    }
+    drop(reader);
+    writer.join();
+    late_reader.join();
+    assert_eq!(LOCK.waiting_threads(), 0);
+    assert_eq!(LOCK.reader_count(), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn released_from_interrupt() {
+    use curi_os::interrupts::{self, IrqLine, IrqReturn};
+
+    serial_print!("released_from_interrupt... ");
+    static SIGNAL: Semaphore = Semaphore::new(0);
+
+    fn signal() -> IrqReturn {
+        SIGNAL.release();
+        IrqReturn::Handled
+    }
+
+    let handle = interrupts::register_irq(IrqLine::Apic(3), signal).expect("line in use");
+    let waiter = thread::spawn(|| SIGNAL.acquire());
+    thread::yield_now();
+    unsafe { asm!("int $$0x33" :::: "volatile") };
+    waiter.join();
+    handle.unregister();
+    assert_eq!(SIGNAL.available_permits(), 0);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,231 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use curi_os::scheduler::{self, Priority};
+use curi_os::thread::{self, stack::KernelStack, ThreadState};
+use curi_os::time::Instant;
+use curi_os::timer;
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_and_join() {
+    serial_print!("spawn_and_join... ");
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let handle = thread::spawn(|| RAN.store(true, Ordering::SeqCst));
+    assert!(handle.id() != thread::current());
+    handle.join();
+    assert!(RAN.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn yield_interleaves() {
+    serial_print!("yield_interleaves... ");
+    static STEP: AtomicUsize = AtomicUsize::new(0);
+
+    // each thread waits for its turn, so they only finish if yielding
+    // passes the CPU around
+    let handles: Vec<_> = (0..3)
+        .map(|turn| {
+            thread::spawn(move || {
+                for round in 0..3 {
+                    while STEP.load(Ordering::SeqCst) != round * 3 + turn {
+                        thread::yield_now();
+                    }
+                    STEP.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(STEP.load(Ordering::SeqCst), 9);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_blocks() {
+    serial_print!("sleep_blocks... ");
+    let start = timer::ticks();
+    thread::sleep(Duration::from_millis(50));
+    assert!(timer::ticks() - start >= timer::duration_to_ticks(Duration::from_millis(50)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_lasts_at_least_duration() {
+    serial_print!("sleep_lasts_at_least_duration... ");
+    let duration = Duration::from_millis(30);
+    let start = Instant::now();
+    thread::sleep(duration);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= duration, "woke after {:?}", elapsed);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    serial_print!("busy_thread_is_preempted... ");
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    // the spinner never yields, so the boot thread only wakes up from its
+    // sleep if the timer preempts the spinner
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    thread::sleep(Duration::from_millis(30));
+    STOP.store(true, Ordering::SeqCst);
+    spinner.join();
+    assert!(SPINS.load(Ordering::Relaxed) > 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stack_is_guarded() {
+    use curi_os::memory;
+
+    serial_print!("stack_is_guarded... ");
+    let stack = KernelStack::allocate().expect("no kernel stack");
+    assert!(memory::walk_page_tables(stack.bottom()).is_mapped());
+    assert!(memory::walk_page_tables(stack.top() - 1u64).is_mapped());
+    assert!(!memory::walk_page_tables(stack.guard_page().start_address()).is_mapped());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn higher_priority_runs_first() {
+    serial_print!("higher_priority_runs_first... ");
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    static LOW_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+    static HIGH_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+
+    // the boot thread keeps the CPU while it spawns the low priority
+    // thread, but gives way to the high priority one immediately
+    let low = thread::spawn_with_priority(Priority::Low, || {
+        LOW_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+    });
+    assert_eq!(LOW_RAN_AT.load(Ordering::SeqCst), 0);
+    let high = thread::spawn_with_priority(Priority::High, || {
+        HIGH_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+    });
+    assert_eq!(HIGH_RAN_AT.load(Ordering::SeqCst), 1);
+
+    high.join();
+    low.join();
+    assert_eq!(LOW_RAN_AT.load(Ordering::SeqCst), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn woken_from_interrupt() {
+    use curi_os::interrupts::{self, IrqLine, IrqReturn};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    serial_print!("woken_from_interrupt... ");
+    static WAITER: AtomicU64 = AtomicU64::new(u64::max_value());
+    static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+    fn signal() -> IrqReturn {
+        SIGNALLED.store(true, Ordering::SeqCst);
+        let waiter = WAITER.load(Ordering::SeqCst);
+        if waiter != u64::max_value() {
+            scheduler::wake(thread::ThreadId::from_u64(waiter));
+        }
+        IrqReturn::Handled
+    }
+
+    let handle = interrupts::register_irq(IrqLine::Apic(2), signal).expect("line in use");
+    let waiter = thread::spawn(|| {
+        WAITER.store(thread::current().as_u64(), Ordering::SeqCst);
+        without_interrupts(|| {
+            while !SIGNALLED.load(Ordering::SeqCst) {
+                scheduler::block_current();
+            }
+        });
+    });
+    while WAITER.load(Ordering::SeqCst) == u64::max_value() {
+        thread::yield_now();
+    }
+    unsafe { asm!("int $$0x32" :::: "volatile") };
+    waiter.join();
+    handle.unregister();
+    assert!(SIGNALLED.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn time_slice_is_configurable() {
+    serial_print!("time_slice_is_configurable... ");
+    scheduler::set_time_slice(Duration::from_millis(50));
+    assert_eq!(scheduler::time_slice(), Duration::from_millis(50));
+    scheduler::set_time_slice(scheduler::DEFAULT_TIME_SLICE);
+    assert_eq!(scheduler::time_slice(), scheduler::DEFAULT_TIME_SLICE);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn dump_lists_threads() {
+    serial_print!("dump_lists_threads... ");
+    // the idle thread runs while the boot thread sleeps
+    thread::sleep(Duration::from_millis(20));
+    let threads = scheduler::threads();
+
+    let idle = threads.iter().find(|info| info.idle).expect("no idle thread");
+    assert_eq!(idle.state, ThreadState::Ready);
+    assert_eq!(idle.priority, Priority::Low);
+    assert!(idle.cpu_time > Duration::from_secs(0));
+
+    let current = threads
+        .iter()
+        .find(|info| info.id == thread::current())
+        .expect("no current thread");
+    assert!(!current.idle);
+    assert_eq!(current.state, ThreadState::Running);
+    assert_eq!(current.priority, Priority::Normal);
+    assert!(current.cpu_time > Duration::from_secs(0));
+
+    serial_println!();
+    scheduler::dump();
+    serial_println!("[ok]");
+}
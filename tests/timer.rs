@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use curi_os::task::{executor::Executor, Task};
+use curi_os::time::{self, TimedOut};
+use curi_os::timer;
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialisation failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+/// Runs expired timer callbacks until `done` returns true, failing the test
+/// if that takes longer than a second.
+fn run_until(mut done: impl FnMut() -> bool) {
+    let deadline = timer::ticks() + timer::TICKS_PER_SECOND;
+    while !done() {
+        assert!(timer::ticks() < deadline, "timed out");
+        timer::run_expired();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Runs `future` on an executor until it completes, failing the test if
+/// that takes longer than a second.
+fn block_on(future: impl Future<Output = ()> + 'static) {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(future));
+    run_until(|| {
+        executor.run_ready_tasks();
+        executor.is_empty()
+    });
+}
+
+#[test_case]
+fn ticks_advance() {
+    serial_print!("ticks_advance... ");
+    let start = timer::ticks();
+    while timer::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn one_shot() {
+    serial_print!("one_shot... ");
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    let start = timer::ticks();
+    timer::after(Duration::from_millis(50), || FIRED.store(true, Ordering::SeqCst));
+    run_until(|| FIRED.load(Ordering::SeqCst));
+    // the tick that was running when it was scheduled does not count
+    assert!(timer::ticks() - start > timer::duration_to_ticks(Duration::from_millis(50)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn periodic_until_cancelled() {
+    serial_print!("periodic_until_cancelled... ");
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let handle = timer::every(Duration::from_millis(20), || {
+        COUNT.fetch_add(1, Ordering::SeqCst);
+    });
+    run_until(|| COUNT.load(Ordering::SeqCst) >= 3);
+    assert!(handle.cancel());
+
+    let count = COUNT.load(Ordering::SeqCst);
+    let deadline = timer::ticks() + 10;
+    run_until(|| timer::ticks() >= deadline);
+    assert_eq!(COUNT.load(Ordering::SeqCst), count);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cancelled_one_shot() {
+    serial_print!("cancelled_one_shot... ");
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    let handle = timer::after(Duration::from_millis(20), || FIRED.store(true, Ordering::SeqCst));
+    assert!(handle.cancel());
+    assert!(!handle.cancel());
+
+    let deadline = timer::ticks() + 5;
+    run_until(|| timer::ticks() >= deadline);
+    assert!(!FIRED.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_waits() {
+    serial_print!("sleep_waits... ");
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let start = timer::ticks();
+    block_on(async {
+        time::sleep(Duration::from_millis(30)).await;
+        DONE.store(true, Ordering::SeqCst);
+    });
+    assert!(DONE.load(Ordering::SeqCst));
+    // the partly elapsed tick at the start does not count
+    assert!(timer::ticks() - start > timer::duration_to_ticks(Duration::from_millis(30)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn with_timeout_completes() {
+    serial_print!("with_timeout_completes... ");
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+
+    block_on(async {
+        let fast = async {
+            time::sleep(Duration::from_millis(10)).await;
+            42
+        };
+        let result = time::with_timeout(Duration::from_millis(200), fast).await;
+        assert_eq!(result, Ok(42));
+        RESULT.store(42, Ordering::SeqCst);
+    });
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn with_timeout_expires() {
+    serial_print!("with_timeout_expires... ");
+    static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+    let start = timer::ticks();
+    block_on(async {
+        let slow = time::sleep(Duration::from_secs(60));
+        let result = time::with_timeout(Duration::from_millis(20), slow).await;
+        TIMED_OUT.store(result == Err(TimedOut), Ordering::SeqCst);
+    });
+    assert!(TIMED_OUT.load(Ordering::SeqCst));
+    assert!(timer::ticks() - start < timer::TICKS_PER_SECOND);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use curi_os::memory;
+use curi_os::usermode::{self, UserExit};
+use curi_os::{scheduler, thread, timer};
+use curi_os::{serial_print, serial_println};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::BootInfoFrameAllocator;
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+/// Start of the pages mapped for the user programs of the tests.
+const USER_START: u64 = 0x_6666_0000_0000;
+
+static NEXT_PAGE: AtomicU64 = AtomicU64::new(USER_START);
+
+/// Maps a fresh user accessible page.
+fn user_page() -> Page {
+    let addr = NEXT_PAGE.fetch_add(4096, Ordering::Relaxed);
+    let page = Page::containing_address(VirtAddr::new(addr));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    memory::map_pages(page, 1, flags).expect("failed to map a user page");
+    page
+}
+
+/// Copies `program` to a user page and returns its entry point.
+fn load(program: &[u8]) -> VirtAddr {
+    let entry = user_page().start_address();
+    let code: *mut u8 = entry.as_mut_ptr();
+    unsafe { core::ptr::copy_nonoverlapping(program.as_ptr(), code, program.len()) };
+    entry
+}
+
+/// Returns the top of a fresh user stack.
+fn user_stack() -> VirtAddr {
+    user_page().start_address() + 4096u64
+}
+
+fn run(program: &[u8]) -> (VirtAddr, UserExit) {
+    let entry = load(program);
+    let exit = unsafe { usermode::enter(entry, user_stack()) };
+    (entry, exit)
+}
+
+#[test_case]
+fn exits_with_code() {
+    serial_print!("exits_with_code... ");
+    // mov $42, %edi; int $0x80
+    let (_, exit) = run(&[0xbf, 42, 0, 0, 0, 0xcd, 0x80]);
+    assert_eq!(exit, UserExit::Exited(42));
+    // the kernel continues with interrupts enabled
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn runs_in_ring_3() {
+    serial_print!("runs_in_ring_3... ");
+    // mov %cs, %edi; int $0x80
+    let (_, exit) = run(&[0x8c, 0xcf, 0xcd, 0x80]);
+    match exit {
+        UserExit::Exited(cs) => assert_eq!(cs & 3, 3),
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn exception_returns_to_kernel() {
+    serial_print!("exception_returns_to_kernel... ");
+    // ud2
+    let (entry, exit) = run(&[0x0f, 0x0b]);
+    assert_eq!(
+        exit,
+        UserExit::Exception {
+            vector: 6,
+            error_code: None,
+            instruction_pointer: entry,
+        }
+    );
+    // hlt, which is privileged
+    let (entry, exit) = run(&[0xf4]);
+    assert_eq!(
+        exit,
+        UserExit::Exception {
+            vector: 13,
+            error_code: Some(0),
+            instruction_pointer: entry,
+        }
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_gs_does_not_break_the_kernel() {
+    serial_print!("user_gs_does_not_break_the_kernel... ");
+    // mov %ss, %eax; mov %eax, %gs; ud2
+    let (entry, exit) = run(&[0x8c, 0xd0, 0x8e, 0xe8, 0x0f, 0x0b]);
+    assert_eq!(
+        exit,
+        UserExit::Exception {
+            vector: 6,
+            error_code: None,
+            instruction_pointer: entry + 4u64,
+        }
+    );
+
+    // mov %ss, %eax; mov %eax, %gs; mov $ITERATIONS, %ecx;
+    // 1: dec %rcx; jnz 1b; mov $7, %edi; int $0x80
+    const ITERATIONS: u32 = 20_000_000;
+    let mut program = Vec::new();
+    program.extend_from_slice(&[0x8c, 0xd0, 0x8e, 0xe8, 0xb9]);
+    program.extend_from_slice(&ITERATIONS.to_le_bytes());
+    program.extend_from_slice(&[0x48, 0xff, 0xc9, 0x75, 0xfb, 0xbf, 7, 0, 0, 0, 0xcd, 0x80]);
+    let ticks = timer::ticks();
+    let (_, exit) = run(&program);
+    assert_eq!(exit, UserExit::Exited(7));
+    // interrupts from user mode found the per-CPU data meanwhile
+    assert!(timer::ticks() > ticks);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn kernel_memory_is_protected() {
+    serial_print!("kernel_memory_is_protected... ");
+    static SECRET: u64 = 0x5ec7e7;
+
+    // movabs $SECRET, %rax; mov (%rax), %rdi; int $0x80
+    let address = &SECRET as *const u64 as u64;
+    let mut program = Vec::new();
+    program.extend_from_slice(&[0x48, 0xb8]);
+    program.extend_from_slice(&address.to_le_bytes());
+    program.extend_from_slice(&[0x48, 0x8b, 0x38, 0xcd, 0x80]);
+    let (entry, exit) = run(&program);
+    assert_eq!(
+        exit,
+        UserExit::PageFault {
+            address: VirtAddr::new(address),
+            error_code: PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE,
+            instruction_pointer: entry + 10u64,
+        }
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn threads_are_preempted_in_user_mode() {
+    serial_print!("threads_are_preempted_in_user_mode... ");
+    const ITERATIONS: u32 = 50_000_000;
+    static EXITS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+    // mov $code, %edi; push %rdi; mov $ITERATIONS, %ecx;
+    // 1: dec %rcx; jnz 1b; pop %rdi; int $0x80
+    let program = |code: u32| {
+        let mut program = Vec::new();
+        program.push(0xbf);
+        program.extend_from_slice(&code.to_le_bytes());
+        program.extend_from_slice(&[0x57, 0xb9]);
+        program.extend_from_slice(&ITERATIONS.to_le_bytes());
+        program.extend_from_slice(&[0x48, 0xff, 0xc9, 0x75, 0xfb, 0x5f, 0xcd, 0x80]);
+        program
+    };
+    let ticks = timer::ticks();
+    let threads: Vec<_> = (0..2)
+        .map(|i| {
+            let entry = load(&program(100 + i as u32));
+            let stack = user_stack();
+            thread::spawn(move || {
+                let exit = unsafe { usermode::enter(entry, stack) };
+                if let UserExit::Exited(code) = exit {
+                    EXITS[i].store(code, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join();
+    }
+    assert_eq!(EXITS[0].load(Ordering::SeqCst), 100);
+    assert_eq!(EXITS[1].load(Ordering::SeqCst), 101);
+    // the timer kept running while the programs did
+    assert!(timer::ticks() > ticks);
+    serial_println!("[ok]");
+}
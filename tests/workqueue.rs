@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(curi_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use curi_os::scheduler;
+use curi_os::sync::Semaphore;
+use curi_os::thread;
+use curi_os::workqueue::{self, Work, WorkQueue};
+use curi_os::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use curi_os::allocator;
+    use curi_os::memory::{self, BootInfoFrameAllocator};
+
+    curi_os::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    memory::init_frame_allocator(frame_allocator);
+    scheduler::init();
+    workqueue::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    curi_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn closures_run_in_order() {
+    use alloc::vec::Vec;
+    use curi_os::sync::Mutex;
+
+    serial_print!("closures_run_in_order... ");
+    static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    let queue = WorkQueue::new("ordered", 1);
+    for n in 0..5 {
+        queue.queue_fn(move || ORDER.lock().push(n));
+    }
+    queue.flush();
+    assert_eq!(*ORDER.lock(), [0, 1, 2, 3, 4]);
+    assert_eq!(queue.completed(), 5);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pending_work_is_queued_once() {
+    serial_print!("pending_work_is_queued_once... ");
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static COUNT: Work = Work::new(count);
+
+    fn count() {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let queue = WorkQueue::new("once", 1);
+    assert!(queue.queue(&COUNT));
+    assert!(!queue.queue(&COUNT));
+    queue.flush();
+    assert!(!COUNT.is_pending());
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+    // it can be queued again once it ran
+    assert!(queue.queue(&COUNT));
+    queue.flush();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cancelled_work_does_not_run() {
+    serial_print!("cancelled_work_does_not_run... ");
+    static GATE: Semaphore = Semaphore::new(0);
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static COUNT: Work = Work::new(count);
+
+    fn count() {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // the only worker is blocked, so the item stays queued
+    let queue = WorkQueue::new("cancel", 1);
+    queue.queue_fn(|| GATE.acquire());
+    assert!(queue.queue(&COUNT));
+    assert!(queue.cancel(&COUNT));
+    assert!(!queue.cancel(&COUNT));
+    assert_eq!(queue.pending(), 0);
+    GATE.release();
+    queue.flush();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn concurrency_is_limited() {
+    serial_print!("concurrency_is_limited... ");
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+
+    let queue = WorkQueue::new("limited", 2);
+    assert_eq!(queue.max_active(), 2);
+    for _ in 0..5 {
+        queue.queue_fn(|| {
+            let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+            if inside > MAX_INSIDE.load(Ordering::SeqCst) {
+                MAX_INSIDE.store(inside, Ordering::SeqCst);
+            }
+            thread::yield_now();
+            INSIDE.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    queue.flush();
+    assert_eq!(MAX_INSIDE.load(Ordering::SeqCst), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn queued_by_threaded_irq() {
+    use curi_os::interrupts::{self, IrqLine, IrqReturn};
+
+    serial_print!("queued_by_threaded_irq... ");
+    static DONE: Semaphore = Semaphore::new(0);
+    static FINISH: Work = Work::new(finish);
+
+    fn acknowledge() -> IrqReturn {
+        IrqReturn::Handled
+    }
+
+    fn finish() {
+        // blocking is fine in process context
+        thread::yield_now();
+        DONE.release();
+    }
+
+    let handle = interrupts::register_threaded_irq(IrqLine::Apic(5), acknowledge, &FINISH)
+        .expect("line in use");
+    unsafe { asm!("int $$0x35" :::: "volatile") };
+    DONE.acquire();
+    handle.unregister();
+    workqueue::system().flush();
+    serial_println!("[ok]");
+}